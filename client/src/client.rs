@@ -0,0 +1,433 @@
+use crate::messages::*;
+use eyre::{eyre, Result};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio::sync::Mutex;
+use tokio::time::{timeout, Duration};
+use tracing::{debug, warn};
+
+pub const DEFAULT_SOCKET: &str = "/tmp/hardware.sock";
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A connection to spine, the bytes read past the last response on it and the id of the next
+/// request
+struct Connection {
+    stream: UnixStream,
+    pending: Vec<u8>,
+    next_id: u64,
+}
+impl Connection {
+    async fn open(path: &Path) -> Result<Self> {
+        let stream = UnixStream::connect(path)
+            .await
+            .map_err(|e| eyre!("Could not connect to spine at {}: {}", path.display(), e))?;
+        let mut connection = Self {
+            stream,
+            pending: Vec::new(),
+            next_id: 0,
+        };
+        // Its reply is skipped like any other nobody waits for
+        let version = HardwareRequest::SetProtocolVersion {
+            version: PROTOCOL_VERSION,
+        };
+        connection.send(version).await?;
+        Ok(connection)
+    }
+    async fn subscribe(path: &Path, topic: &str) -> Result<Self> {
+        let mut connection = Self::open(path).await?;
+        let subscribe = HardwareRequest::Subscribe {
+            topic: topic.to_string(),
+        };
+        connection.send(subscribe).await?;
+        Ok(connection)
+    }
+    /// Sends `request` with a fresh id, which its reply comes back with
+    async fn send(&mut self, request: HardwareRequest) -> Result<u64> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let encoded = serde_json::to_string(&RequestEnvelope { id, request })?;
+        self.stream.write_all(encoded.as_bytes()).await?;
+        debug!("Sent {}", encoded);
+        Ok(id)
+    }
+    /// Waits for the next response spine writes
+    async fn next_envelope(&mut self) -> Result<ResponseEnvelope> {
+        let mut buf = [0u8; 4096];
+        loop {
+            let mut envelopes =
+                serde_json::Deserializer::from_slice(&self.pending).into_iter::<ResponseEnvelope>();
+            match envelopes.next() {
+                Some(Ok(envelope)) => {
+                    let consumed = envelopes.byte_offset();
+                    self.pending.drain(..consumed);
+                    return Ok(envelope);
+                }
+                Some(Err(e)) if !e.is_eof() => {
+                    self.pending.clear();
+                    return Err(eyre!("Could not decode what spine wrote: {}", e));
+                }
+                _ => {}
+            }
+            let read = self.stream.read(&mut buf).await?;
+            if read == 0 {
+                return Err(eyre!("spine closed the connection"));
+            }
+            self.pending.extend_from_slice(&buf[..read]);
+        }
+    }
+    /// Waits for the reply to request `id`, skipping replies to requests given up on and values
+    /// published on subscriptions
+    async fn reply(&mut self, id: u64) -> Result<HardwareResponse> {
+        loop {
+            let envelope = self.next_envelope().await?;
+            if envelope.id == Some(id) {
+                return Ok(envelope.response);
+            }
+            debug!("Skipping {:?}", envelope);
+        }
+    }
+}
+
+/// Longest pulse servos are written, bounding how far a servo at an unknown position can sweep
+const LONGEST_PULSE_US: u16 = 2500;
+
+/// How long a sweep to `target` can take at `max_velocity` microseconds per second, wherever the
+/// servo starts from, plus `margin` for spine to reply
+fn sweep_timeout(target: u16, max_velocity: f32, margin: Duration) -> Duration {
+    let distance = target.max(LONGEST_PULSE_US.saturating_sub(target));
+    let sweep = match max_velocity {
+        v if v > 0.0 => Duration::try_from_secs_f32(distance as f32 / v).unwrap_or(Duration::MAX),
+        // Spine jumps straight to the target
+        _ => Duration::ZERO,
+    };
+    sweep.saturating_add(margin)
+}
+
+/// The reply to `request`, or why spine couldn't carry it out
+fn carried_out(request: &HardwareRequest, response: HardwareResponse) -> Result<HardwareResponse> {
+    match response {
+        HardwareResponse::Error(reason) => {
+            Err(eyre!("spine could not carry out {:?}: {}", request, reason))
+        }
+        response => Ok(response),
+    }
+}
+
+fn unexpected(response: HardwareResponse) -> eyre::Report {
+    eyre!("Unexpected response from spine: {:?}", response)
+}
+
+/// Client of a running spine. Requests go out one at a time on a single connection, their replies
+/// told apart by request id, and subscriptions and servo moves get a connection of their own. A
+/// connection spine has dropped is reopened on the next request.
+pub struct Client {
+    path: PathBuf,
+    timeout: Duration,
+    connection: Mutex<Option<Connection>>,
+}
+impl Client {
+    pub async fn connect(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let connection = Connection::open(&path).await?;
+        Ok(Self {
+            path,
+            timeout: DEFAULT_TIMEOUT,
+            connection: Mutex::new(Some(connection)),
+        })
+    }
+    /// How long to wait for a reply before giving up on it
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+    /// Sends `request` and waits for its reply, `HardwareResponse::Ok` for requests that don't
+    /// return anything. Fails if spine couldn't carry out the request.
+    pub async fn request(&self, request: HardwareRequest) -> Result<HardwareResponse> {
+        let mut connection = self.connection.lock().await;
+        let sent = match connection.as_mut() {
+            Some(open) => open.send(request.clone()).await,
+            None => Err(eyre!("Not connected")),
+        };
+        let (open, id) = match sent {
+            Ok(id) => (
+                connection
+                    .as_mut()
+                    .expect("request was sent on a connection"),
+                id,
+            ),
+            Err(e) => {
+                warn!("Reconnecting to spine: {}", e);
+                *connection = None;
+                let mut reopened = Connection::open(&self.path).await?;
+                let id = reopened.send(request.clone()).await?;
+                (connection.insert(reopened), id)
+            }
+        };
+        let reply = match timeout(self.timeout, open.reply(id)).await {
+            Ok(reply) => reply,
+            // A reply turning up later is skipped by its id, the connection can stay
+            Err(_) => {
+                return Err(eyre!(
+                    "spine did not reply to {:?} within {:?}",
+                    request,
+                    self.timeout
+                ))
+            }
+        };
+        if reply.is_err() {
+            *connection = None;
+        }
+        carried_out(&request, reply?)
+    }
+    async fn send(&self, request: HardwareRequest) -> Result<()> {
+        self.request(request).await.map(|_| ())
+    }
+    /// Gets every value published on `topic` from now on, e.g. `ODOMETRY_TOPIC`
+    pub async fn subscribe(&self, topic: &str) -> Result<Subscription> {
+        Ok(Subscription {
+            path: self.path.clone(),
+            topic: topic.to_string(),
+            connection: Connection::subscribe(&self.path, topic).await?,
+        })
+    }
+
+    pub async fn motor_write(&self, motor: &str, command: Vec<u8>) -> Result<()> {
+        let motor = motor.to_string();
+        self.send(HardwareRequest::MotorWrite { motor, command })
+            .await
+    }
+    /// Speed from -1.0 (full reverse) to 1.0 (full forward)
+    pub async fn motor_set(&self, motor: &str, speed: f32) -> Result<()> {
+        let motor = motor.to_string();
+        self.send(HardwareRequest::MotorSet { motor, speed }).await
+    }
+    pub async fn servo_write(&self, servo: &str, position: u16) -> Result<()> {
+        let servo = servo.to_string();
+        self.send(HardwareRequest::ServoWrite {
+            servo,
+            position,
+            duty: None,
+            start: None,
+        })
+        .await
+    }
+    pub async fn servo_set_angle(&self, servo: &str, degrees: f32) -> Result<()> {
+        let servo = servo.to_string();
+        self.send(HardwareRequest::ServoSetAngle { servo, degrees })
+            .await
+    }
+    /// Sweeps a servo to `target` microseconds and returns where it ended up. The move gets a
+    /// connection of its own, so other requests aren't held up while the servo sweeps.
+    pub async fn servo_move(&self, servo: &str, target: u16, max_velocity: f32) -> Result<u16> {
+        let servo = servo.to_string();
+        let request = HardwareRequest::ServoMove {
+            servo,
+            target,
+            max_velocity,
+        };
+        let limit = sweep_timeout(target, max_velocity, self.timeout);
+        let mut connection = Connection::open(&self.path).await?;
+        let id = connection.send(request.clone()).await?;
+        match timeout(limit, connection.reply(id)).await {
+            Ok(reply) => match carried_out(&request, reply?)? {
+                HardwareResponse::MoveComplete(position) => Ok(position),
+                response => Err(unexpected(response)),
+            },
+            Err(_) => Err(eyre!(
+                "spine did not finish {:?} within {:?}",
+                request,
+                limit
+            )),
+        }
+    }
+    pub async fn encoder_read(&self, encoder: &str) -> Result<Reading> {
+        let encoder = encoder.to_string();
+        match self
+            .request(HardwareRequest::EncoderRead { encoder })
+            .await?
+        {
+            HardwareResponse::EncoderValue(counts) => Ok(Reading::Raw(counts)),
+            HardwareResponse::Measurement { value, unit } => {
+                Ok(Reading::Measurement { value, unit })
+            }
+            response => Err(unexpected(response)),
+        }
+    }
+    pub async fn encoder_zero(&self, encoder: &str) -> Result<()> {
+        let encoder = encoder.to_string();
+        self.send(HardwareRequest::EncoderZero { encoder }).await
+    }
+    pub async fn encoder_reset(&self) -> Result<()> {
+        self.send(HardwareRequest::EncoderReset).await
+    }
+    pub async fn sensor_read(&self, sensor: &str) -> Result<Reading> {
+        let sensor = sensor.to_string();
+        match self.request(HardwareRequest::SensorRead { sensor }).await? {
+            HardwareResponse::SensorValue(raw) => Ok(Reading::Raw(raw.into())),
+            HardwareResponse::Measurement { value, unit } => {
+                Ok(Reading::Measurement { value, unit })
+            }
+            response => Err(unexpected(response)),
+        }
+    }
+    pub async fn switch_read(&self, switch: &str) -> Result<bool> {
+        let switch = switch.to_string();
+        match self.request(HardwareRequest::SwitchRead { switch }).await? {
+            HardwareResponse::SwitchOn(on) => Ok(on),
+            response => Err(unexpected(response)),
+        }
+    }
+    /// Level a GPIO was last driven to, mostly useful with mock GPIOs
+    pub async fn gpio_read(&self, pin: u64) -> Result<u8> {
+        match self.request(HardwareRequest::GpioRead { pin }).await? {
+            HardwareResponse::GpioValue(value) => Ok(value),
+            response => Err(unexpected(response)),
+        }
+    }
+    pub async fn led_write(&self, led: &str, state: u8) -> Result<()> {
+        let led = led.to_string();
+        self.send(HardwareRequest::LedWrite { led, state }).await
+    }
+    pub async fn led_pattern(&self, led: &str, pattern: LedPattern) -> Result<()> {
+        let led = led.to_string();
+        self.send(HardwareRequest::LedPattern { led, pattern })
+            .await
+    }
+    pub async fn status_indicate(&self, colour: Colour, pattern: LedPattern) -> Result<()> {
+        self.send(HardwareRequest::StatusIndicate { colour, pattern })
+            .await
+    }
+    pub async fn status_release(&self) -> Result<()> {
+        self.send(HardwareRequest::StatusRelease).await
+    }
+    pub async fn estop(&self, engaged: bool) -> Result<()> {
+        self.send(HardwareRequest::EStop { engaged }).await
+    }
+    pub async fn joint_set_target(&self, joint: &str, counts: i64) -> Result<()> {
+        let joint = joint.to_string();
+        self.send(HardwareRequest::JointSetTarget { joint, counts })
+            .await
+    }
+    pub async fn joint_release(&self, joint: &str) -> Result<()> {
+        let joint = joint.to_string();
+        self.send(HardwareRequest::JointRelease { joint }).await
+    }
+    pub async fn wheel_set_velocity(
+        &self,
+        wheel: &str,
+        velocity: f32,
+        unit: VelocityUnit,
+    ) -> Result<()> {
+        let wheel = wheel.to_string();
+        self.send(HardwareRequest::WheelSetVelocity {
+            wheel,
+            velocity,
+            unit,
+        })
+        .await
+    }
+    pub async fn wheel_release(&self, wheel: &str) -> Result<()> {
+        let wheel = wheel.to_string();
+        self.send(HardwareRequest::WheelRelease { wheel }).await
+    }
+    pub async fn drive_twist(&self, linear: f32, angular: f32) -> Result<()> {
+        self.send(HardwareRequest::DriveTwist { linear, angular })
+            .await
+    }
+    pub async fn odometry_read(&self) -> Result<Odometry> {
+        match self.request(HardwareRequest::OdometryRead).await? {
+            HardwareResponse::Odometry(odometry) => Ok(odometry),
+            response => Err(unexpected(response)),
+        }
+    }
+    pub async fn reset_odometry(&self) -> Result<()> {
+        self.send(HardwareRequest::ResetOdometry).await
+    }
+    pub async fn battery_read(&self) -> Result<BatteryState> {
+        match self.request(HardwareRequest::BatteryRead).await? {
+            HardwareResponse::Battery(battery) => Ok(battery),
+            response => Err(unexpected(response)),
+        }
+    }
+    pub async fn imu_read(&self) -> Result<ImuState> {
+        match self.request(HardwareRequest::ImuRead).await? {
+            HardwareResponse::Imu(imu) => Ok(imu),
+            response => Err(unexpected(response)),
+        }
+    }
+    pub async fn i2c_read(&self, device: &str) -> Result<Readings> {
+        let device = device.to_string();
+        match self.request(HardwareRequest::I2cRead { device }).await? {
+            HardwareResponse::Readings(readings) => Ok(readings),
+            response => Err(unexpected(response)),
+        }
+    }
+    pub async fn status(&self) -> Result<SystemStatus> {
+        match self.request(HardwareRequest::StatusRead).await? {
+            HardwareResponse::Status(status) => Ok(status),
+            response => Err(unexpected(response)),
+        }
+    }
+    pub async fn config_dump(&self) -> Result<Value> {
+        match self.request(HardwareRequest::ConfigDump).await? {
+            HardwareResponse::Config(config) => Ok(config),
+            response => Err(unexpected(response)),
+        }
+    }
+}
+
+/// Values published on a topic, resubscribing if spine drops the connection
+pub struct Subscription {
+    path: PathBuf,
+    topic: String,
+    connection: Connection,
+}
+impl Subscription {
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+    /// Waits for the next value published on the topic
+    pub async fn next(&mut self) -> Result<HardwareResponse> {
+        loop {
+            let envelope = match self.connection.next_envelope().await {
+                Ok(envelope) => envelope,
+                Err(e) => {
+                    warn!("Lost subscription to {}, resubscribing: {}", self.topic, e);
+                    self.connection = Connection::subscribe(&self.path, &self.topic).await?;
+                    continue;
+                }
+            };
+            // Replies to the requests setting up the subscription
+            if envelope.id.is_none() {
+                return Ok(envelope.response);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sweeps_are_given_the_longest_distance_they_can_cover() {
+        let margin = Duration::from_secs(1);
+        // From 0us, or from the longest pulse down to a short target
+        assert_eq!(sweep_timeout(2000, 1000.0, margin), Duration::from_secs(3));
+        assert_eq!(sweep_timeout(500, 1000.0, margin), Duration::from_secs(3));
+        assert_eq!(sweep_timeout(3000, 1000.0, margin), Duration::from_secs(4));
+    }
+
+    #[test]
+    fn sweeps_without_a_velocity_limit_only_get_the_margin() {
+        let margin = Duration::from_secs(1);
+        assert_eq!(sweep_timeout(1500, 0.0, margin), margin);
+        assert_eq!(sweep_timeout(1500, -10.0, margin), margin);
+        assert_eq!(
+            sweep_timeout(1500, f32::MIN_POSITIVE, margin),
+            Duration::MAX
+        );
+    }
+}
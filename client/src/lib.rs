@@ -0,0 +1,7 @@
+//! Requests and responses understood by spine, and an async client sending them over its socket.
+//! spine itself uses these types, so a change to the protocol shows up wherever it breaks a client.
+mod client;
+mod messages;
+
+pub use client::{Client, Subscription, DEFAULT_SOCKET};
+pub use messages::*;
@@ -0,0 +1,323 @@
+//! Requests and responses on spine's socket, JSON encoded
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Topics spine publishes on besides the readings of every `system.i2c` device, under its name
+pub const ODOMETRY_TOPIC: &str = "odometry";
+pub const BATTERY_TOPIC: &str = "battery";
+pub const IMU_TOPIC: &str = "imu";
+
+/// Responses are written as a newline-delimited `ResponseEnvelope`, one for every request.
+/// Version 1 writes only the bare value a response carries, e.g. `42`, and nothing for `Ok`.
+pub const PROTOCOL_VERSION: u8 = 2;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum HardwareRequest {
+    MotorWrite {
+        motor: String,
+        command: Vec<u8>,
+    },
+    /// Speed from -1.0 (full reverse) to 1.0 (full forward), whatever drives the motor
+    MotorSet {
+        motor: String,
+        speed: f32,
+    },
+    ServoWrite {
+        servo: String,
+        position: u16,
+        duty: Option<u16>,
+        start: Option<u16>,
+    },
+    /// Angle from the calibrated neutral, clamped to the calibrated range
+    ServoSetAngle {
+        servo: String,
+        degrees: f32,
+    },
+    /// Sweep to `target` microseconds at no more than `max_velocity` microseconds per second,
+    /// replies with the final position once the sweep is over
+    ServoMove {
+        servo: String,
+        target: u16,
+        max_velocity: f32,
+    },
+    /// Hold a joint at `counts` of its encoder
+    JointSetTarget {
+        joint: String,
+        counts: i64,
+    },
+    /// Stop holding a joint, stopping its motor
+    JointRelease {
+        joint: String,
+    },
+    /// Keep a wheel at a velocity, using its encoder
+    WheelSetVelocity {
+        wheel: String,
+        velocity: f32,
+        unit: VelocityUnit,
+    },
+    /// Stop controlling a wheel's velocity, stopping its motor
+    WheelRelease {
+        wheel: String,
+    },
+    /// Drive at `linear` m/s and `angular` rad/s
+    DriveTwist {
+        linear: f32,
+        angular: f32,
+    },
+    OdometryRead,
+    ResetOdometry,
+    /// Latest reading of the battery monitor
+    BatteryRead,
+    /// Latest readings of a device in `system.i2c`
+    I2cRead {
+        device: String,
+    },
+    /// Latest orientation, acceleration and angular velocity from the IMU
+    ImuRead,
+    /// Get every new value published on `topic`, e.g. "odometry"
+    Subscribe {
+        topic: String,
+    },
+    Unsubscribe {
+        topic: String,
+    },
+    EncoderRead {
+        encoder: String,
+    },
+    /// Resets one encoder, unlike `EncoderReset` which resets all of them
+    EncoderZero {
+        encoder: String,
+    },
+    SwitchRead {
+        switch: String,
+    },
+    /// Debugging aid, makes a switch on mock GPIOs read `on` until the next one
+    SwitchSimulate {
+        switch: String,
+        on: bool,
+    },
+    /// Debugging aid, the level a GPIO of a switch, LED or H-bridge was last driven to
+    GpioRead {
+        pin: u64,
+    },
+    LedWrite {
+        led: String,
+        state: u8,
+    },
+    LedPattern {
+        led: String,
+        pattern: LedPattern,
+    },
+    StatusIndicate {
+        colour: Colour,
+        pattern: LedPattern,
+    },
+    StatusRelease,
+    EStop {
+        engaged: bool,
+    },
+    EncoderReset,
+    SensorRead {
+        sensor: String,
+    },
+    /// Whether the PAD is connected, the e-stop, battery level and tilt
+    StatusRead,
+    /// The configuration spine is running with, defaults filled in
+    ConfigDump,
+    /// Switches how responses are written on this connection, see `PROTOCOL_VERSION`
+    SetProtocolVersion {
+        version: u8,
+    },
+}
+/// A request and the id its response is written back with. Bare requests are understood too, their
+/// responses have no id.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RequestEnvelope {
+    pub id: u64,
+    pub request: HardwareRequest,
+}
+
+/// A response as written by protocol version 2, followed by a newline
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResponseEnvelope {
+    /// Id of the request answered, none for values published on a subscription
+    pub id: Option<u64>,
+    /// Device the request was about, or the topic a value was published on
+    pub device: Option<String>,
+    /// Milliseconds since the Unix epoch when the response was written
+    pub timestamp: u64,
+    pub response: HardwareResponse,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum HardwareResponse {
+    EncoderValue(i64),
+    /// A reading converted to `unit`
+    Measurement {
+        value: f64,
+        unit: String,
+    },
+    SensorValue(u16),
+    SwitchOn(bool),
+    GpioValue(u8),
+    MoveComplete(u16),
+    Odometry(Odometry),
+    Battery(BatteryState),
+    Readings(Readings),
+    Imu(ImuState),
+    Status(SystemStatus),
+    Config(Value),
+    /// Why spine couldn't carry out the request, e.g. an unknown device or a failed read
+    Error(String),
+    Ok,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum VelocityUnit {
+    CountsPerSecond,
+    MetresPerSecond,
+}
+
+/// Pose integrated from the drive encoders since the last reset, and the current velocities
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Odometry {
+    pub x: f32,
+    pub y: f32,
+    pub heading: f32,
+    pub linear: f32,
+    pub angular: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct BatteryState {
+    pub voltage: f32,
+    /// Only known when measured with an INA219/INA226
+    pub current: Option<f32>,
+    /// State of charge from 0.0 to 1.0, estimated from the voltage
+    pub soc: f32,
+}
+
+/// How much the motors are allowed to do at the current battery voltage
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BatteryLevel {
+    #[default]
+    Normal,
+    /// Motor commands are scaled down
+    Low,
+    /// Motor commands are refused
+    Cutoff,
+}
+
+/// Named values read from a device, with the unit as the suffix of the name, e.g. "current_a"
+pub type Readings = BTreeMap<String, f64>;
+
+/// Orientation in degrees, acceleration in m/s² and angular velocity in degrees per second, about
+/// the x (roll), y (pitch) and z (yaw) axes
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct ImuState {
+    pub roll: f32,
+    pub pitch: f32,
+    pub yaw: f32,
+    pub accel: [f32; 3],
+    pub gyro: [f32; 3],
+}
+impl ImuState {
+    /// Angle between the z axis and vertical
+    pub fn tilt(&self) -> f32 {
+        let (roll, pitch) = (self.roll.to_radians(), self.pitch.to_radians());
+        (roll.cos() * pitch.cos())
+            .clamp(-1.0, 1.0)
+            .acos()
+            .to_degrees()
+    }
+}
+
+/// State of the rover that is shown on the status LEDs when no client has taken them over
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct SystemStatus {
+    pub pad_connected: bool,
+    pub estop: bool,
+    pub battery: BatteryLevel,
+    /// Tilted past `imu.max_tilt_deg`
+    pub tilted: bool,
+}
+impl SystemStatus {
+    /// Whether motors have to be kept stopped, whatever clients ask for
+    pub fn motors_inhibited(&self) -> bool {
+        self.estop || self.tilted || self.battery == BatteryLevel::Cutoff
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LedPattern {
+    Off,
+    Solid,
+    Blink {
+        hz: f32,
+    },
+    /// Two short flashes every second
+    Heartbeat,
+    /// `count` short flashes followed by a pause
+    Pulse {
+        count: u8,
+    },
+}
+
+impl LedPattern {
+    /// Blink rates have to be positive and finite, anything else is refused
+    pub fn is_valid(&self) -> bool {
+        match self {
+            Self::Blink { hz } => hz.is_finite() && *hz > 0.0,
+            _ => true,
+        }
+    }
+}
+
+/// Colours made by mixing the `red`, `green` and `blue` entries of `system.status_leds`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Colour {
+    Red,
+    Green,
+    Blue,
+    Amber,
+    Cyan,
+    Magenta,
+    White,
+}
+
+/// An encoder or sensor reading, raw or converted to `unit` when the device is calibrated
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Reading {
+    Raw(i64),
+    Measurement { value: f64, unit: String },
+}
+
+impl HardwareRequest {
+    /// Name of the device, joint, wheel or topic the request is about
+    pub fn device(&self) -> Option<&str> {
+        match self {
+            Self::MotorWrite { motor, .. } | Self::MotorSet { motor, .. } => Some(motor),
+            Self::ServoWrite { servo, .. }
+            | Self::ServoSetAngle { servo, .. }
+            | Self::ServoMove { servo, .. } => Some(servo),
+            Self::JointSetTarget { joint, .. } | Self::JointRelease { joint } => Some(joint),
+            Self::WheelSetVelocity { wheel, .. } | Self::WheelRelease { wheel } => Some(wheel),
+            Self::EncoderRead { encoder } | Self::EncoderZero { encoder } => Some(encoder),
+            Self::SwitchRead { switch } | Self::SwitchSimulate { switch, .. } => Some(switch),
+            Self::LedWrite { led, .. } | Self::LedPattern { led, .. } => Some(led),
+            Self::SensorRead { sensor } => Some(sensor),
+            Self::I2cRead { device } => Some(device),
+            Self::Subscribe { topic } | Self::Unsubscribe { topic } => Some(topic),
+            Self::OdometryRead => Some(ODOMETRY_TOPIC),
+            Self::BatteryRead => Some(BATTERY_TOPIC),
+            Self::ImuRead => Some(IMU_TOPIC),
+            _ => None,
+        }
+    }
+}
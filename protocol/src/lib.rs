@@ -0,0 +1,114 @@
+//! Messages between spine and the PAD, postcard encoded. Variants are matched by index, so new
+//! ones have to be appended.
+//!
+//! On the wire every message is a COBS frame ending in [`FRAME_END`], which appears nowhere else
+//! in it, so a reader that lost track of the stream resyncs at the next terminator. Firmware built
+//! before the framing sends bare messages and has to be rebuilt against this crate. Operations that
+//! expect a reply document its type, the PAD sends nothing back for the others.
+#![no_std]
+
+use postcard::accumulator::CobsAccumulator;
+use serde::{Deserialize, Serialize};
+
+pub use postcard::accumulator::FeedResult;
+pub use postcard::Error;
+
+pub const ENCODER_COUNT: usize = 6;
+/// Longest frame of an operation or a reply, terminator included
+pub const MAX_MESSAGE_LEN: usize = 64;
+/// Last byte of every frame
+pub const FRAME_END: u8 = 0;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Operation {
+    KeepAlive,
+    SabertoothWrite(u8, u8),
+    /// Reads ADC channel 0, superseded by AdcRead but kept so the variant indices match the PAD
+    SensorRead,
+    /// Replied to with [`EncoderCounts`]
+    EncoderRead,
+    PwmStartEndWrite(u8, u16, u16),
+    /// Replied to with the firmware version, a string
+    VersionReport,
+    EncoderReset,
+    PwmFrequencyWrite(u16),
+    /// Replied to with an [`AdcReading`]
+    AdcRead(u8),
+    /// A frame for the Smartelex driver on the port
+    SmartelexWrite(u8, [u8; 5]),
+}
+
+/// Raw count of every encoder, in port order
+pub type EncoderCounts = [i32; ENCODER_COUNT];
+pub type AdcReading = u16;
+
+/// Collects frames from bytes as they arrive, without an allocator, for the firmware.
+/// `feed` returns the bytes after a completed frame, to be fed again.
+pub type Accumulator = CobsAccumulator<MAX_MESSAGE_LEN>;
+
+/// Encodes an operation or a reply into `buf`, returning the frame to write, terminator included
+pub fn encode<'a, T: Serialize>(message: &T, buf: &'a mut [u8]) -> Result<&'a mut [u8], Error> {
+    postcard::to_slice_cobs(message, buf)
+}
+
+/// Decodes a single frame, with or without its terminator. The frame is decoded in place.
+pub fn decode<'a, T: Deserialize<'a>>(frame: &'a mut [u8]) -> Result<T, Error> {
+    postcard::from_bytes_cobs(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_end_in_the_terminator_only() {
+        let mut buf = [0u8; MAX_MESSAGE_LEN];
+        let frame = encode(&Operation::PwmStartEndWrite(0, 0, 0), &mut buf).unwrap();
+        let (&last, body) = frame.split_last().unwrap();
+        assert_eq!(last, FRAME_END);
+        assert!(!body.contains(&FRAME_END));
+        assert_eq!(decode::<Operation>(frame).unwrap(), Operation::PwmStartEndWrite(0, 0, 0));
+    }
+
+    #[test]
+    fn longest_reply_fits() {
+        let mut buf = [0u8; MAX_MESSAGE_LEN];
+        let counts: EncoderCounts = [i32::MIN; ENCODER_COUNT];
+        let frame = encode(&counts, &mut buf).unwrap();
+        assert_eq!(decode::<EncoderCounts>(frame).unwrap(), counts);
+    }
+
+    #[test]
+    fn accumulator_joins_frames_split_across_reads() {
+        let mut buf = [0u8; MAX_MESSAGE_LEN];
+        let frame = encode(&Operation::AdcRead(3), &mut buf).unwrap();
+        let (first, second) = frame.split_at(1);
+        let mut accumulator = Accumulator::new();
+        assert!(matches!(accumulator.feed::<Operation>(first), FeedResult::Consumed));
+        match accumulator.feed::<Operation>(second) {
+            FeedResult::Success { data, remaining } => {
+                assert_eq!(data, Operation::AdcRead(3));
+                assert!(remaining.is_empty());
+            }
+            _ => panic!("Frame was not completed"),
+        }
+    }
+
+    #[test]
+    fn accumulator_resyncs_after_junk() {
+        let mut buf = [0u8; MAX_MESSAGE_LEN];
+        let frame = encode(&Operation::KeepAlive, &mut buf).unwrap();
+        let mut bytes = [0xff; 12];
+        bytes[8] = FRAME_END;
+        bytes[9..9 + frame.len()].copy_from_slice(frame);
+        let mut accumulator = Accumulator::new();
+        let remaining = match accumulator.feed::<Operation>(&bytes[..9 + frame.len()]) {
+            FeedResult::DeserError(remaining) => remaining,
+            _ => panic!("Junk was decoded"),
+        };
+        assert!(matches!(
+            accumulator.feed::<Operation>(remaining),
+            FeedResult::Success { data: Operation::KeepAlive, .. }
+        ));
+    }
+}
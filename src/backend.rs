@@ -0,0 +1,428 @@
+use crate::server::{HardwareRequest, HardwareResponse};
+use crate::status::{Colour, LedPattern};
+use eyre::{eyre, Result};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::future::Future;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time::Duration;
+use tracing::{debug, error, warn};
+
+const KEEP_ALIVE_PERIOD: Duration = Duration::from_millis(800);
+
+/// Kinds of named devices a backend can own. Names only have to be unique within a kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DeviceKind {
+    Motor,
+    Servo,
+    Encoder,
+    Sensor,
+    Switch,
+    Led,
+    /// A GPIO of the host by number, for reading back what it was driven to
+    Gpio,
+}
+
+/// Hardware that spine drives devices through, e.g. the PAD or the GPIOs of the host.
+/// Requests reach a backend already lowered by `Config::lower_request`, and only for the devices
+/// it lists in `devices`. Everything a backend doesn't support fails by default.
+pub trait Backend: Send + 'static {
+    /// Every device this backend owns, requests are routed to it by these names
+    fn devices(&self) -> Vec<(DeviceKind, String)>;
+
+    /// Called once before any request, and again whenever `keep_alive` fails
+    fn connect(&mut self) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+    fn keep_alive(&mut self) -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Writes the motor driver's own command bytes
+    fn write_motor(
+        &mut self,
+        motor: &str,
+        _command: &[u8],
+    ) -> impl Future<Output = Result<()>> + Send {
+        let motor = motor.to_string();
+        async move { Err(eyre!("Motor {} can't be written to", motor)) }
+    }
+    /// Sets a motor to a speed from -1.0 to 1.0
+    fn set_motor(&mut self, motor: &str, _speed: f32) -> impl Future<Output = Result<()>> + Send {
+        let motor = motor.to_string();
+        async move { Err(eyre!("Motor {} can't be set to a speed", motor)) }
+    }
+    /// Writes a pulse of `position` microseconds, or the raw `duty` and `start` ticks if given
+    fn write_servo(
+        &mut self,
+        servo: &str,
+        _position: u16,
+        _duty: Option<u16>,
+        _start: Option<u16>,
+    ) -> impl Future<Output = Result<()>> + Send {
+        let servo = servo.to_string();
+        async move { Err(eyre!("Servo {} can't be written to", servo)) }
+    }
+    fn read_encoder(&mut self, encoder: &str) -> impl Future<Output = Result<i64>> + Send {
+        let encoder = encoder.to_string();
+        async move { Err(eyre!("Encoder {} can't be read", encoder)) }
+    }
+    fn zero_encoder(&mut self, encoder: &str) -> impl Future<Output = Result<()>> + Send {
+        let encoder = encoder.to_string();
+        async move { Err(eyre!("Encoder {} can't be zeroed", encoder)) }
+    }
+    /// Resets every encoder of this backend
+    fn reset_encoders(&mut self) -> impl Future<Output = Result<()>> + Send {
+        async { Err(eyre!("Encoders can't be reset")) }
+    }
+    fn read_sensor(&mut self, sensor: &str) -> impl Future<Output = Result<u16>> + Send {
+        let sensor = sensor.to_string();
+        async move { Err(eyre!("Sensor {} can't be read", sensor)) }
+    }
+    fn read_switch(&mut self, switch: &str) -> impl Future<Output = Result<bool>> + Send {
+        let switch = switch.to_string();
+        async move { Err(eyre!("Switch {} can't be read", switch)) }
+    }
+    /// Drives a switch's input as if it had been pressed or released, for testing
+    fn set_input(&mut self, switch: &str, _on: bool) -> impl Future<Output = Result<()>> + Send {
+        let switch = switch.to_string();
+        async move { Err(eyre!("Switch {} can't be set", switch)) }
+    }
+    /// The level a GPIO was last driven to, or reads as if it's an input
+    fn read_gpio(&mut self, pin: u64) -> impl Future<Output = Result<u8>> + Send {
+        async move { Err(eyre!("GPIO {} can't be read", pin)) }
+    }
+    fn write_led(
+        &mut self,
+        led: &str,
+        _pattern: LedPattern,
+    ) -> impl Future<Output = Result<()>> + Send {
+        let led = led.to_string();
+        async move { Err(eyre!("LED {} can't be written to", led)) }
+    }
+    /// Shows `colour` with `pattern` on the status LEDs instead of the system status
+    fn indicate_status(
+        &mut self,
+        _colour: Colour,
+        _pattern: LedPattern,
+    ) -> impl Future<Output = Result<()>> + Send {
+        async { Err(eyre!("No status LEDs")) }
+    }
+    /// Hands the status LEDs back to the system status
+    fn release_status(&mut self) -> impl Future<Output = Result<()>> + Send {
+        async { Err(eyre!("No status LEDs")) }
+    }
+}
+
+/// Kind of the device a request is for, `None` for requests that aren't for a device of a backend
+fn kind_of(req: &HardwareRequest) -> Option<DeviceKind> {
+    match req {
+        HardwareRequest::MotorWrite { .. } | HardwareRequest::MotorSet { .. } => Some(DeviceKind::Motor),
+        HardwareRequest::ServoWrite { .. }
+        | HardwareRequest::ServoSetAngle { .. }
+        | HardwareRequest::ServoMove { .. } => Some(DeviceKind::Servo),
+        HardwareRequest::EncoderRead { .. } | HardwareRequest::EncoderZero { .. } => Some(DeviceKind::Encoder),
+        HardwareRequest::SensorRead { .. } => Some(DeviceKind::Sensor),
+        HardwareRequest::SwitchRead { .. } | HardwareRequest::SwitchSimulate { .. } => Some(DeviceKind::Switch),
+        HardwareRequest::LedWrite { .. } | HardwareRequest::LedPattern { .. } => Some(DeviceKind::Led),
+        HardwareRequest::GpioRead { .. } => Some(DeviceKind::Gpio),
+        _ => None,
+    }
+}
+
+/// The device a request is routed by, named as in `HardwareRequest::device`
+fn device_of(req: &HardwareRequest) -> Option<(DeviceKind, String)> {
+    let kind = kind_of(req)?;
+    match req {
+        // GPIOs are named by their number
+        HardwareRequest::GpioRead { pin } => Some((kind, pin.to_string())),
+        req => req.device().map(|name| (kind, name.to_string())),
+    }
+}
+
+/// Calls the method of `backend` that carries out `req`
+async fn call<B: Backend>(backend: &mut B, req: HardwareRequest) -> Result<HardwareResponse> {
+    match req {
+        HardwareRequest::MotorWrite { motor, command } => {
+            backend.write_motor(&motor, &command).await?
+        }
+        HardwareRequest::MotorSet { motor, speed } => backend.set_motor(&motor, speed).await?,
+        HardwareRequest::ServoWrite {
+            servo,
+            position,
+            duty,
+            start,
+        } => backend.write_servo(&servo, position, duty, start).await?,
+        HardwareRequest::EncoderRead { encoder } => {
+            return Ok(HardwareResponse::EncoderValue(
+                backend.read_encoder(&encoder).await?,
+            ))
+        }
+        HardwareRequest::EncoderZero { encoder } => backend.zero_encoder(&encoder).await?,
+        HardwareRequest::EncoderReset => backend.reset_encoders().await?,
+        HardwareRequest::SensorRead { sensor } => {
+            return Ok(HardwareResponse::SensorValue(
+                backend.read_sensor(&sensor).await?,
+            ))
+        }
+        HardwareRequest::SwitchRead { switch } => {
+            return Ok(HardwareResponse::SwitchOn(
+                backend.read_switch(&switch).await?,
+            ))
+        }
+        HardwareRequest::SwitchSimulate { switch, on } => backend.set_input(&switch, on).await?,
+        HardwareRequest::GpioRead { pin } => {
+            return Ok(HardwareResponse::GpioValue(backend.read_gpio(pin).await?))
+        }
+        HardwareRequest::LedWrite { led, state } => {
+            let pattern = if state == 0 {
+                LedPattern::Off
+            } else {
+                LedPattern::Solid
+            };
+            backend.write_led(&led, pattern).await?
+        }
+        HardwareRequest::LedPattern { led, pattern } => backend.write_led(&led, pattern).await?,
+        HardwareRequest::StatusIndicate { colour, pattern } => {
+            backend.indicate_status(colour, pattern).await?
+        }
+        HardwareRequest::StatusRelease => backend.release_status().await?,
+        req => return Err(eyre!("Request can't be handled by a backend: {:?}", req)),
+    }
+    Ok(HardwareResponse::Ok)
+}
+
+#[derive(Debug)]
+struct BackendRequest {
+    body: HardwareRequest,
+    tx: oneshot::Sender<HardwareResponse>,
+}
+
+/// Sends requests to a backend running in its own task
+#[derive(Clone)]
+pub struct BackendHandle {
+    name: String,
+    tx: mpsc::Sender<BackendRequest>,
+    connected: watch::Receiver<bool>,
+}
+impl BackendHandle {
+    /// Replies once the backend has carried out the request, with `HardwareResponse::Error` if it
+    /// failed
+    pub async fn request(&self, req: HardwareRequest) -> HardwareResponse {
+        debug!("Sending request to {}", self.name);
+        let (tx, rx) = oneshot::channel();
+        if self
+            .tx
+            .send(BackendRequest { body: req, tx })
+            .await
+            .is_err()
+        {
+            error!("Backend {} has stopped", self.name);
+            return HardwareResponse::Error(format!("Backend {} has stopped", self.name));
+        }
+        match rx.await {
+            Ok(response) => {
+                debug!("Received response from {}: {:?}", self.name, response);
+                response
+            }
+            // The backend sends why it failed, unless it stopped meanwhile
+            Err(_) => {
+                error!("{} could not respond to the request", self.name);
+                HardwareResponse::Error(format!("{} could not respond to the request", self.name))
+            }
+        }
+    }
+    /// Whether the backend's last keep alive succeeded
+    pub fn connected(&self) -> watch::Receiver<bool> {
+        self.connected.clone()
+    }
+}
+
+/// Runs `backend` in its own task, connecting it and keeping it alive
+fn spawn_backend<B: Backend>(name: &str, mut backend: B) -> BackendHandle {
+    let (tx, mut requests) = mpsc::channel::<BackendRequest>(100);
+    let (send_connected, connected) = watch::channel(false);
+    let handle = BackendHandle {
+        name: name.to_string(),
+        tx,
+        connected,
+    };
+    let name = name.to_string();
+    tokio::spawn(async move {
+        backend.connect().await;
+        let mut interval = tokio::time::interval(KEEP_ALIVE_PERIOD);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    let alive = backend
+                        .keep_alive()
+                        .await
+                        .map_err(|e| error!("Error keeping {} alive: {}", name, e))
+                        .is_ok();
+                    send_connected.send_if_modified(|connected| std::mem::replace(connected, alive) != alive);
+                    if !alive {
+                        warn!("Lost connection to {}, trying to reconnect...", name);
+                        backend.connect().await;
+                    }
+                }
+                req = requests.recv() => {
+                    let Some(BackendRequest { body, tx }) = req else {
+                        break;
+                    };
+                    debug!("{} got request: {:?}", name, body);
+                    match call(&mut backend, body).await {
+                        Ok(response) => {
+                            tx.send(response).ok();
+                        }
+                        Err(e) => {
+                            error!("Error responding to request on {}: {:?}", name, e);
+                            tx.send(HardwareResponse::Error(e.to_string())).ok();
+                        }
+                    }
+                }
+            }
+        }
+    });
+    handle
+}
+
+/// Every running backend, and which one owns each device
+#[derive(Default)]
+pub struct Backends {
+    handles: Vec<BackendHandle>,
+    routes: HashMap<(DeviceKind, String), usize>,
+}
+impl Backends {
+    /// Starts `backend` and routes requests for its devices to it.
+    /// Devices already owned by an earlier backend stay with it.
+    pub fn spawn<B: Backend>(&mut self, name: &str, backend: B) -> BackendHandle {
+        let devices = backend.devices();
+        let handle = spawn_backend(name, backend);
+        let index = self.handles.len();
+        for device in devices {
+            match self.routes.entry(device) {
+                Entry::Occupied(entry) if *entry.get() == index => {}
+                Entry::Occupied(entry) => {
+                    let (kind, name) = entry.key();
+                    let previous = &self.handles[*entry.get()].name;
+                    warn!(
+                        "{:?} {} is owned by both {} and {}, using {}",
+                        kind, name, previous, handle.name, previous
+                    );
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(index);
+                }
+            }
+        }
+        self.handles.push(handle.clone());
+        handle
+    }
+    /// Backends owning at least one device of `kind`
+    fn owning(&self, kind: DeviceKind) -> impl Iterator<Item = &BackendHandle> {
+        self.handles
+            .iter()
+            .enumerate()
+            .filter(move |(index, _)| {
+                self.routes
+                    .iter()
+                    .any(|((k, _), i)| *k == kind && i == index)
+            })
+            .map(|(_, handle)| handle)
+    }
+    /// Sends `req` to the backend owning its device, or to every backend it concerns
+    pub async fn dispatch(&self, req: HardwareRequest) -> HardwareResponse {
+        let broadcast_to = match &req {
+            HardwareRequest::EncoderReset => Some(DeviceKind::Encoder),
+            HardwareRequest::StatusIndicate { .. } | HardwareRequest::StatusRelease => {
+                Some(DeviceKind::Led)
+            }
+            _ => None,
+        };
+        if let Some(kind) = broadcast_to {
+            // Every backend gets the request, even after one of them failed it
+            let mut response = HardwareResponse::Ok;
+            for handle in self.owning(kind) {
+                if let error @ HardwareResponse::Error(_) = handle.request(req.clone()).await {
+                    response = error;
+                }
+            }
+            return response;
+        }
+        let handle = device_of(&req)
+            .and_then(|device| self.routes.get(&device))
+            .map(|&index| &self.handles[index]);
+        match handle {
+            Some(handle) => handle.request(req).await,
+            None => {
+                warn!("No backend found for {:?}", req);
+                HardwareResponse::Error(format!("No backend found for {:?}", req))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Owns an encoder without supporting any request
+    struct Unsupported;
+    impl Backend for Unsupported {
+        fn devices(&self) -> Vec<(DeviceKind, String)> {
+            vec![(DeviceKind::Encoder, "arm_base".to_string())]
+        }
+    }
+
+    #[test]
+    fn devices_are_named_as_in_requests() {
+        let set = HardwareRequest::MotorSet {
+            motor: "drive_front".to_string(),
+            speed: 0.5,
+        };
+        assert_eq!(device_of(&set), Some((DeviceKind::Motor, "drive_front".to_string())));
+        let read = HardwareRequest::GpioRead { pin: 397 };
+        assert_eq!(device_of(&read), Some((DeviceKind::Gpio, "397".to_string())));
+        // Named, but not after a device of a backend
+        let joint = HardwareRequest::JointRelease {
+            joint: "arm_base".to_string(),
+        };
+        assert_eq!(device_of(&joint), None);
+        assert_eq!(device_of(&HardwareRequest::EStop { engaged: true }), None);
+    }
+
+    #[tokio::test]
+    async fn failed_reads_reply_with_an_error() {
+        let mut backends = Backends::default();
+        backends.spawn("test", Unsupported);
+        let read = HardwareRequest::EncoderRead {
+            encoder: "arm_base".to_string(),
+        };
+        match backends.dispatch(read).await {
+            HardwareResponse::Error(reason) => assert_eq!(reason, "Encoder arm_base can't be read"),
+            response => panic!("Read replied with {:?}", response),
+        }
+        let unknown = HardwareRequest::SensorRead {
+            sensor: "arm_base".to_string(),
+        };
+        assert!(matches!(backends.dispatch(unknown).await, HardwareResponse::Error(_)));
+    }
+
+    #[tokio::test]
+    async fn failed_writes_reply_with_an_error() {
+        let mut backends = Backends::default();
+        backends.spawn("test", Unsupported);
+        let zero = HardwareRequest::EncoderZero {
+            encoder: "arm_base".to_string(),
+        };
+        match backends.dispatch(zero).await {
+            HardwareResponse::Error(reason) => assert_eq!(reason, "Encoder arm_base can't be zeroed"),
+            response => panic!("Zero replied with {:?}", response),
+        }
+        // Broadcasts too
+        assert!(matches!(
+            backends.dispatch(HardwareRequest::EncoderReset).await,
+            HardwareResponse::Error(_)
+        ));
+    }
+}
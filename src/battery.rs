@@ -0,0 +1,175 @@
+use crate::config::{BatteryConfig, BatterySource, Config};
+use crate::i2c::{Ina2xx, Ina2xxKind};
+use crate::server::{handle_request, stop_all_motors, Channels, HardwareRequest, HardwareResponse};
+use eyre::{eyre, Result};
+use std::sync::Arc;
+use tokio::time::Duration;
+use tracing::{error, info, trace, warn};
+
+pub use spine_client::{BatteryLevel, BatteryState, BATTERY_TOPIC};
+
+/// Level after reading `voltage` at `level`, with hysteresis so a sagging battery doesn't flicker
+fn next_level(level: BatteryLevel, voltage: f32, config: &BatteryConfig) -> BatteryLevel {
+    let BatteryConfig {
+        warn_voltage,
+        cutoff_voltage,
+        hysteresis,
+        ..
+    } = *config;
+    match level {
+        _ if voltage < cutoff_voltage => BatteryLevel::Cutoff,
+        BatteryLevel::Cutoff if voltage < cutoff_voltage + hysteresis => BatteryLevel::Cutoff,
+        BatteryLevel::Normal if voltage < warn_voltage => BatteryLevel::Low,
+        BatteryLevel::Low | BatteryLevel::Cutoff if voltage < warn_voltage + hysteresis => BatteryLevel::Low,
+        _ => BatteryLevel::Normal,
+    }
+}
+
+async fn read_pad_sensor(config: &Config, channels: &mut Channels, sensor: &str) -> Result<f32> {
+    let read = HardwareRequest::SensorRead {
+        sensor: sensor.to_string(),
+    };
+    match handle_request(config, read, channels).await {
+        HardwareResponse::Measurement { value, .. } => Ok(value as f32),
+        response => Err(eyre!(
+            "Battery sensor {} needs a calibration to volts, got {:?}",
+            sensor,
+            response
+        )),
+    }
+}
+
+/// Reads the battery at a fixed rate, publishing it on `BATTERY_TOPIC` and limiting the motors
+/// when its voltage drops.
+pub async fn monitor_battery(config: Arc<Config>, mut channels: Channels) {
+    let Some(battery) = config.battery.clone() else {
+        return;
+    };
+    let mut ina = match &battery.source {
+        BatterySource::PadSensor { .. } => None,
+        BatterySource::Ina219 {
+            bus,
+            address,
+            shunt_ohms,
+        }
+        | BatterySource::Ina226 {
+            bus,
+            address,
+            shunt_ohms,
+        } => {
+            let kind = match battery.source {
+                BatterySource::Ina219 { .. } => Ina2xxKind::Ina219,
+                _ => Ina2xxKind::Ina226,
+            };
+            let bus = bus.as_deref().unwrap_or(&config.system.pca9685_path);
+            match Ina2xx::new(bus, kind, *address, *shunt_ohms) {
+                Ok(ina) => Some(ina),
+                Err(e) => {
+                    error!("Could not open battery monitor on {}: {}", bus, e);
+                    return;
+                }
+            }
+        }
+    };
+    let mut interval =
+        tokio::time::interval(Duration::from_secs_f32(1.0 / battery.rate_hz.max(0.01)));
+    loop {
+        interval.tick().await;
+        let reading = match (&battery.source, ina.as_mut()) {
+            (BatterySource::PadSensor { sensor }, _) => {
+                read_pad_sensor(&config, &mut channels, sensor)
+                    .await
+                    .map(|voltage| (voltage, None))
+            }
+            (_, Some(ina)) => ina
+                .bus_voltage()
+                .and_then(|voltage| Ok((voltage, Some(ina.current()?)))),
+            (_, None) => unreachable!(),
+        };
+        let (voltage, current) = match reading {
+            Ok(reading) => reading,
+            Err(e) => {
+                warn!("Could not read battery: {}", e);
+                continue;
+            }
+        };
+        let soc = ((voltage - battery.empty_voltage)
+            / (battery.full_voltage - battery.empty_voltage))
+            .clamp(0.0, 1.0);
+        let state = BatteryState {
+            voltage,
+            current,
+            soc,
+        };
+        trace!("Battery: {:?}", state);
+        channels
+            .telemetry
+            .publish(BATTERY_TOPIC, HardwareResponse::Battery(state));
+
+        let previous = channels.status.borrow().battery;
+        let level = next_level(previous, voltage, &battery);
+        if level != previous {
+            channels.status.send_modify(|status| status.battery = level);
+            match level {
+                BatteryLevel::Cutoff => {
+                    error!(
+                        "Battery at {}V is below cutoff, stopping all motors",
+                        voltage
+                    );
+                    stop_all_motors(&config, &mut channels).await;
+                }
+                BatteryLevel::Low => warn!("Battery at {}V is low, limiting motors", voltage),
+                BatteryLevel::Normal => info!("Battery at {}V is back to normal", voltage),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use BatteryLevel::*;
+
+    fn config() -> BatteryConfig {
+        toml::from_str(
+            r#"
+            type = "pad_sensor"
+            sensor = "battery"
+            empty_voltage = 20.0
+            full_voltage = 25.0
+            warn_voltage = 22.0
+            cutoff_voltage = 21.0
+            hysteresis = 0.25
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn drops_at_the_thresholds() {
+        let config = config();
+        assert_eq!(next_level(Normal, 22.0, &config), Normal);
+        assert_eq!(next_level(Normal, 21.99, &config), Low);
+        assert_eq!(next_level(Normal, 21.0, &config), Low);
+        assert_eq!(next_level(Normal, 20.99, &config), Cutoff);
+        assert_eq!(next_level(Low, 20.99, &config), Cutoff);
+    }
+
+    #[test]
+    fn low_recovers_past_the_hysteresis() {
+        let config = config();
+        assert_eq!(next_level(Low, 22.0, &config), Low);
+        assert_eq!(next_level(Low, 22.24, &config), Low);
+        assert_eq!(next_level(Low, 22.25, &config), Normal);
+    }
+
+    #[test]
+    fn cutoff_recovers_past_the_hysteresis() {
+        let config = config();
+        assert_eq!(next_level(Cutoff, 21.0, &config), Cutoff);
+        assert_eq!(next_level(Cutoff, 21.24, &config), Cutoff);
+        assert_eq!(next_level(Cutoff, 21.25, &config), Low);
+        assert_eq!(next_level(Cutoff, 22.24, &config), Low);
+        assert_eq!(next_level(Cutoff, 22.25, &config), Normal);
+    }
+}
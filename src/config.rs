@@ -1,57 +1,595 @@
-use crate::server::HardwareRequest;
-use serde::Deserialize;
+use crate::control::{PidConfig, VelocityUnit};
+use crate::gpio::{GpioBackend, MockInput};
+use crate::i2c::I2cDriver;
+use crate::imu::ImuDriver;
+use crate::motor::{MotorCommand, MotorDriver, MotorShaping};
+use crate::sensor::Calibration;
+use crate::server::{HardwareRequest, HardwareResponse};
+use crate::servo::ServoCalibration;
+use crate::sim::SimConfig;
+use eyre::{bail, Result};
+use serde::{Deserialize, Serialize};
+use spine_protocol::ENCODER_COUNT;
 use std::collections::HashMap;
+use std::f32::consts::PI;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::BufReader;
 use tracing::info;
 
-#[derive(Default, Deserialize, Debug)]
+/// A motor on the PAD is either just the port of its Sabertooth, which is then driven on channel 1,
+/// or a table with the port, the driver, its channel and the shaping applied to its speed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum PadMotorConfig {
+    Port(u8),
+    Table {
+        port: u8,
+        #[serde(default)]
+        driver: MotorDriver,
+        #[serde(default = "default_motor_channel")]
+        channel: u8,
+        #[serde(flatten)]
+        shaping: MotorShaping,
+        max_acceleration: Option<f32>,
+    },
+}
+fn default_motor_channel() -> u8 {
+    1
+}
+impl PadMotorConfig {
+    pub fn port(&self) -> u8 {
+        match self {
+            Self::Port(port) | Self::Table { port, .. } => *port,
+        }
+    }
+    pub fn channel(&self) -> u8 {
+        match self {
+            Self::Port(_) => default_motor_channel(),
+            Self::Table { channel, .. } => *channel,
+        }
+    }
+    pub fn driver(&self) -> MotorDriver {
+        match self {
+            Self::Port(_) => MotorDriver::Sabertooth,
+            Self::Table { driver, .. } => *driver,
+        }
+    }
+    pub fn shaping(&self) -> MotorShaping {
+        match self {
+            Self::Port(_) => MotorShaping::default(),
+            Self::Table { shaping, .. } => *shaping,
+        }
+    }
+    pub fn max_acceleration(&self) -> Option<f32> {
+        match self {
+            Self::Port(_) => None,
+            Self::Table {
+                max_acceleration, ..
+            } => *max_acceleration,
+        }
+    }
+}
+/// A servo is either just its output channel, or a table with the channel and its calibration.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ServoConfig {
+    Channel(u8),
+    Calibrated {
+        channel: u8,
+        #[serde(flatten)]
+        calibration: ServoCalibration,
+    },
+}
+impl ServoConfig {
+    pub fn channel(&self) -> u8 {
+        match self {
+            Self::Channel(channel) | Self::Calibrated { channel, .. } => *channel,
+        }
+    }
+    pub fn calibration(&self) -> ServoCalibration {
+        match self {
+            Self::Channel(_) => ServoCalibration::default(),
+            Self::Calibrated { calibration, .. } => *calibration,
+        }
+    }
+}
+/// An encoder is either just its port, reporting counts, or a table with the port and the units
+/// per count it's reported in.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum EncoderConfig {
+    Port(u8),
+    Scaled { port: u8, scale: f64, unit: String },
+}
+impl EncoderConfig {
+    pub fn port(&self) -> u8 {
+        match self {
+            Self::Port(port) | Self::Scaled { port, .. } => *port,
+        }
+    }
+}
+/// An analog sensor is either just its ADC channel, read as raw counts, or a table with the
+/// channel, its calibration and the unit the calibration converts to.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum SensorConfig {
+    Channel(u8),
+    Calibrated {
+        channel: u8,
+        #[serde(flatten)]
+        calibration: Calibration,
+        unit: String,
+    },
+}
+impl SensorConfig {
+    pub fn channel(&self) -> u8 {
+        match self {
+            Self::Channel(channel) | Self::Calibrated { channel, .. } => *channel,
+        }
+    }
+}
+#[derive(Default, Serialize, Deserialize, Debug)]
 pub struct PadConfig {
-    motors: HashMap<String, u8>,
-    encoders: HashMap<String, u8>,
-    servos: HashMap<String, u8>,
+    pub motors: HashMap<String, PadMotorConfig>,
+    pub encoders: HashMap<String, EncoderConfig>,
+    pub servos: HashMap<String, ServoConfig>,
+    #[serde(default)]
+    pub sensors: HashMap<String, SensorConfig>,
+    /// Frequency of the PAD's servo outputs, sent to it on connecting
+    #[serde(default = "default_servo_pwm_freq")]
+    pub pwm_freq: u16,
+    #[serde(default)]
+    pub backend: PadBackend,
+    /// Serial device of the PAD, e.g. the pty of `pad_emulator`. Found by its USB IDs if not set.
+    pub path: Option<String>,
+    /// Model used by the simulated PAD
+    #[serde(default)]
+    pub sim: SimConfig,
+}
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PadBackend {
+    /// The PAD on USB serial
+    #[default]
+    Serial,
+    /// An in-process model of the PAD, also selected with `--simulate`
+    Sim,
+}
+/// An H-bridge is either just its two direction pins, or a table with the direction pins,
+/// an optional enable pin driven with PWM for speed control and the speed below which it's stopped.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum HBridgeConfig {
+    Pins([u64; 2]),
+    Table {
+        pins: [u64; 2],
+        enable: Option<PwmOutputConfig>,
+        #[serde(default)]
+        threshold: f32,
+        #[serde(flatten)]
+        shaping: MotorShaping,
+        max_acceleration: Option<f32>,
+    },
+}
+impl HBridgeConfig {
+    pub fn pins(&self) -> [u64; 2] {
+        match self {
+            Self::Pins(pins) | Self::Table { pins, .. } => *pins,
+        }
+    }
+    pub fn threshold(&self) -> f32 {
+        match self {
+            Self::Pins(_) => 0.0,
+            Self::Table { threshold, .. } => *threshold,
+        }
+    }
+    pub fn shaping(&self) -> MotorShaping {
+        match self {
+            Self::Pins(_) => MotorShaping::default(),
+            Self::Table { shaping, .. } => *shaping,
+        }
+    }
+    pub fn max_acceleration(&self) -> Option<f32> {
+        match self {
+            Self::Pins(_) => None,
+            Self::Table {
+                max_acceleration, ..
+            } => *max_acceleration,
+        }
+    }
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PwmOutputConfig {
+    Sysfs {
+        chip: u32,
+        channel: u32,
+        #[serde(default = "default_hardware_pwm_freq")]
+        frequency: u32,
+    },
+    Pca9685 {
+        channel: u8,
+    },
+    Software {
+        pin: u64,
+        #[serde(default = "default_software_pwm_freq")]
+        frequency: u32,
+    },
+}
+fn default_servo_pwm_freq() -> u16 {
+    60
 }
-#[derive(Deserialize, Debug, Clone)]
+fn default_hardware_pwm_freq() -> u32 {
+    20_000
+}
+fn default_software_pwm_freq() -> u32 {
+    100
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SystemConfig {
-    pub motors: HashMap<String, [u64; 2]>,
+    pub motors: HashMap<String, HBridgeConfig>,
     pub limit_switches: HashMap<String, u64>,
     pub status_leds: HashMap<String, u64>,
     pub pca9685_path: String,
-    pub servos: HashMap<String, u8>,
+    pub servos: HashMap<String, ServoConfig>,
+    /// Frequency of the PCA9685's outputs
+    #[serde(default = "default_servo_pwm_freq")]
+    pub pwm_freq: u16,
+    /// Sensors polled on their own, by name
+    #[serde(default)]
+    pub i2c: HashMap<String, I2cDeviceConfig>,
+    /// Where the limit switches, status LEDs and H-bridges are, also made mock by `--simulate`
+    #[serde(default)]
+    pub gpio: GpioBackend,
+    /// Readings of limit switches on mock GPIOs, by switch name
+    #[serde(default)]
+    pub mock_inputs: HashMap<String, MockInput>,
+    /// How responses are written on new connections, 1 for clients expecting bare values.
+    /// Clients can switch with `SetProtocolVersion`.
+    #[serde(default = "default_protocol_version")]
+    pub protocol_version: u8,
+}
+fn default_protocol_version() -> u8 {
+    spine_client::PROTOCOL_VERSION
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct I2cDeviceConfig {
+    /// Defaults to `pca9685_path`
+    pub bus: Option<String>,
+    pub address: u8,
+    #[serde(flatten)]
+    pub driver: I2cDriver,
+    #[serde(default = "default_i2c_rate")]
+    pub rate_hz: f32,
+}
+fn default_i2c_rate() -> f32 {
+    1.0
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImuConfig {
+    /// Defaults to `system.pca9685_path`
+    pub bus: Option<String>,
+    /// Defaults to the driver's usual address
+    pub address: Option<u8>,
+    #[serde(flatten)]
+    pub driver: ImuDriver,
+    #[serde(default = "default_imu_rate")]
+    pub rate_hz: f32,
+    /// Tilt from upright past which every motor is stopped and motor requests are refused
+    pub max_tilt_deg: Option<f32>,
+    /// How far back below `max_tilt_deg` the rover has to come before motors are allowed again
+    #[serde(default = "default_tilt_hysteresis")]
+    pub tilt_hysteresis_deg: f32,
+}
+fn default_imu_rate() -> f32 {
+    50.0
+}
+fn default_tilt_hysteresis() -> f32 {
+    5.0
+}
+/// A joint held at a position by driving `motor` from the readings of `encoder`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JointConfig {
+    pub encoder: String,
+    pub motor: String,
+    #[serde(flatten)]
+    pub pid: PidConfig,
+    #[serde(default = "default_control_rate")]
+    pub rate_hz: f32,
 }
-#[derive(Deserialize, Debug)]
+/// A wheel kept at a velocity by driving `motor` from the change in readings of `encoder`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WheelConfig {
+    pub encoder: String,
+    pub motor: String,
+    /// Needed to command the wheel in metres per second, derived from `drive` for its wheels
+    pub counts_per_metre: Option<f32>,
+    /// Motor speed per count per second of target velocity
+    #[serde(default)]
+    pub kf: f32,
+    #[serde(flatten)]
+    pub pid: PidConfig,
+    #[serde(default = "default_control_rate")]
+    pub rate_hz: f32,
+}
+fn default_control_rate() -> f32 {
+    20.0
+}
+/// Skid-steer drive, turning twists into left and right velocities and the drive encoders into
+/// odometry. Lengths are in metres.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DriveConfig {
+    pub track_width: f32,
+    pub wheel_radius: f32,
+    /// Encoder counts per revolution of a wheel
+    pub encoder_cpr: f32,
+    pub left_motors: Vec<String>,
+    pub right_motors: Vec<String>,
+    pub left_encoders: Vec<String>,
+    pub right_encoders: Vec<String>,
+    /// Velocity controlled wheels, if set the side is driven through them instead of its motors
+    #[serde(default)]
+    pub left_wheels: Vec<String>,
+    #[serde(default)]
+    pub right_wheels: Vec<String>,
+    /// Velocity at full motor speed, used to drive motors open loop
+    #[serde(default = "default_max_speed")]
+    pub max_speed: f32,
+    #[serde(default = "default_control_rate")]
+    pub rate_hz: f32,
+}
+fn default_max_speed() -> f32 {
+    1.0
+}
+impl DriveConfig {
+    pub fn counts_per_metre(&self) -> f32 {
+        self.encoder_cpr / (2.0 * PI * self.wheel_radius)
+    }
+    pub fn has_wheel(&self, wheel: &str) -> bool {
+        self.left_wheels.iter().chain(&self.right_wheels).any(|name| name == wheel)
+    }
+}
+/// Where the battery voltage is measured
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BatterySource {
+    /// An entry of `pad.sensors`, calibrated to volts
+    PadSensor { sensor: String },
+    /// An INA219 on an I2C bus, `system.pca9685_path` if `bus` is not set
+    Ina219 {
+        bus: Option<String>,
+        #[serde(default = "default_ina_address")]
+        address: u8,
+        shunt_ohms: f32,
+    },
+    Ina226 {
+        bus: Option<String>,
+        #[serde(default = "default_ina_address")]
+        address: u8,
+        shunt_ohms: f32,
+    },
+}
+fn default_ina_address() -> u8 {
+    0x40
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BatteryConfig {
+    #[serde(flatten)]
+    pub source: BatterySource,
+    /// Voltages taken as 0% and 100% charge
+    pub empty_voltage: f32,
+    pub full_voltage: f32,
+    /// Below this motor speeds are scaled by `warn_scale`
+    pub warn_voltage: f32,
+    /// Below this every motor is stopped and motor requests are refused
+    pub cutoff_voltage: f32,
+    #[serde(default = "default_warn_scale")]
+    pub warn_scale: f32,
+    /// How far above a threshold the voltage has to recover before the limit is lifted
+    #[serde(default = "default_hysteresis")]
+    pub hysteresis: f32,
+    #[serde(default = "default_battery_rate")]
+    pub rate_hz: f32,
+}
+fn default_warn_scale() -> f32 {
+    0.5
+}
+fn default_hysteresis() -> f32 {
+    0.2
+}
+fn default_battery_rate() -> f32 {
+    2.0
+}
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
     pub pad: PadConfig,
     pub system: SystemConfig,
+    #[serde(default)]
+    pub joints: HashMap<String, JointConfig>,
+    #[serde(default)]
+    pub wheels: HashMap<String, WheelConfig>,
+    pub drive: Option<DriveConfig>,
+    pub battery: Option<BatteryConfig>,
+    pub imu: Option<ImuConfig>,
 }
-pub enum Handler {
-    Pad(u8),
-    System,
-}
-
 impl Config {
-    pub fn resolve(&self, hrq: &HardwareRequest) -> Option<Handler> {
+    /// Converts the counts read from an encoder into its configured unit, if it has one
+    pub fn scale_encoder(&self, encoder: &str, response: HardwareResponse) -> HardwareResponse {
+        match (self.pad.encoders.get(encoder), response) {
+            (Some(EncoderConfig::Scaled { scale, unit, .. }), HardwareResponse::EncoderValue(counts)) => {
+                HardwareResponse::Measurement {
+                    value: counts as f64 * scale,
+                    unit: unit.clone(),
+                }
+            }
+            (_, response) => response,
+        }
+    }
+    /// Converts the raw counts read from a sensor with its calibration, if it has one
+    pub fn calibrate_sensor(&self, sensor: &str, response: HardwareResponse) -> HardwareResponse {
+        match (self.pad.sensors.get(sensor), response) {
+            (
+                Some(SensorConfig::Calibrated { calibration, unit, .. }),
+                HardwareResponse::SensorValue(raw),
+            ) => HardwareResponse::Measurement {
+                value: calibration.apply(raw),
+                unit: unit.clone(),
+            },
+            (_, response) => response,
+        }
+    }
+    /// Turns requests using names and units into what the hardware behind the name takes
+    pub fn lower_request(&self, hrq: HardwareRequest) -> HardwareRequest {
         match hrq {
-            HardwareRequest::ServoWrite { servo, position: _, duty: _, start: _ } => {
-                self.pad.servos.get(servo).map(|port| Handler::Pad(*port))
+            HardwareRequest::ServoWrite { .. } | HardwareRequest::ServoSetAngle { .. } => {
+                self.lower_servo_request(hrq)
             }
-            HardwareRequest::MotorWrite { motor, command: _ } => self
-                .pad
-                .motors
-                .get(motor)
-                .map(|port| Handler::Pad(*port))
-                .or_else(|| self.system.motors.get(motor).map(|_| Handler::System)),
-            HardwareRequest::EncoderRead { encoder } => self
-                .pad
-                .encoders
-                .get(encoder)
-                .map(|port| Handler::Pad(*port)),
-            HardwareRequest::EncoderReset | HardwareRequest::SensorRead => Some(Handler::Pad(0)),
-            HardwareRequest::SwitchRead { switch: _ }
-            | HardwareRequest::LedWrite { led: _, state: _ } => Some(Handler::System),
+            hrq => self.lower_motor_request(hrq),
         }
     }
+    fn servo_config(&self, servo: &str) -> Option<&ServoConfig> {
+        self.pad.servos.get(servo).or_else(|| self.system.servos.get(servo))
+    }
+    /// Turns a `ServoSetAngle` into a `ServoWrite` of the calibrated pulse width, and keeps
+    /// `ServoWrite` pulse widths within the calibrated endpoints.
+    pub fn lower_servo_request(&self, hrq: HardwareRequest) -> HardwareRequest {
+        match hrq {
+            HardwareRequest::ServoSetAngle { servo, degrees } => {
+                let calibration = self
+                    .servo_config(&servo)
+                    .map(ServoConfig::calibration)
+                    .unwrap_or_default();
+                HardwareRequest::ServoWrite {
+                    position: calibration.angle_to_microseconds(degrees),
+                    servo,
+                    duty: None,
+                    start: None,
+                }
+            }
+            HardwareRequest::ServoWrite {
+                servo,
+                position,
+                duty,
+                start,
+            } => {
+                let position = match self.servo_config(&servo) {
+                    Some(ServoConfig::Calibrated { calibration, .. }) => calibration.clamp(position),
+                    _ => position,
+                };
+                HardwareRequest::ServoWrite {
+                    position,
+                    servo,
+                    duty,
+                    start,
+                }
+            }
+            hrq => hrq,
+        }
+    }
+    /// Driver of a PAD motor, H-bridges take Sabertooth commands in a `MotorWrite` too
+    pub fn motor_driver(&self, motor: &str) -> MotorDriver {
+        self.pad.motors.get(motor).map(PadMotorConfig::driver).unwrap_or_default()
+    }
+    /// Speed asked of a motor by a `MotorWrite`, if the command is valid for the motor's driver.
+    /// `[0]` stops a motor whatever its driver.
+    pub fn command_speed(&self, motor: &str, command: &[u8]) -> Option<f32> {
+        if command == [0] {
+            return Some(0.0);
+        }
+        MotorCommand::parse(self.motor_driver(motor), command)
+            .ok()
+            .map(|command| command.speed().0)
+    }
+    /// Applies the configured shaping to a `MotorWrite` or `MotorSet`, and turns it into what the
+    /// motor's driver takes: a `MotorWrite` of the driver's command for a PAD motor or a
+    /// `MotorSet` for an H-bridge. `[0]` becomes the stop of a PAD motor's driver. Every other
+    /// request is returned as is.
+    pub fn lower_motor_request(&self, hrq: HardwareRequest) -> HardwareRequest {
+        let (motor, speed, channel) = match hrq {
+            // Unshaped, 0 stops both channels of a Sabertooth
+            HardwareRequest::MotorWrite { motor, command } if command.as_slice() == [0] => {
+                return match self.pad.motors.get(&motor) {
+                    Some(pad_motor) => HardwareRequest::MotorWrite {
+                        command: pad_motor.driver().stop(pad_motor.channel()),
+                        motor,
+                    },
+                    None => HardwareRequest::MotorWrite { motor, command },
+                };
+            }
+            // Unless a channel is configured, keep writing to the channel the client wrote to
+            HardwareRequest::MotorWrite { motor, command } => {
+                match MotorCommand::parse(self.motor_driver(&motor), &command) {
+                    Ok(parsed) => {
+                        let (speed, channel) = parsed.speed();
+                        (motor, speed, channel)
+                    }
+                    // Left for the backend to refuse
+                    Err(_) => return HardwareRequest::MotorWrite { motor, command },
+                }
+            }
+            HardwareRequest::MotorSet { motor, speed } => (motor, speed, None),
+            hrq => return hrq,
+        };
+        if let Some(pad_motor) = self.pad.motors.get(&motor) {
+            let speed = pad_motor.shaping().apply(speed);
+            let channel = match (pad_motor, channel) {
+                (PadMotorConfig::Port(_), Some(channel)) => channel,
+                _ => pad_motor.channel(),
+            };
+            HardwareRequest::MotorWrite {
+                command: pad_motor.driver().command(channel, speed),
+                motor,
+            }
+        } else if let Some(h_bridge) = self.system.motors.get(&motor) {
+            HardwareRequest::MotorSet {
+                speed: h_bridge.shaping().apply(speed),
+                motor,
+            }
+        } else {
+            HardwareRequest::MotorSet { motor, speed }
+        }
+    }
+    /// Velocity of a wheel in counts per second, if it has a `counts_per_metre` for velocities in
+    /// metres per second
+    pub fn wheel_counts_per_second(&self, wheel: &str, velocity: f32, unit: VelocityUnit) -> Option<f32> {
+        let counts_per_metre = self.wheels.get(wheel)?.counts_per_metre.or_else(|| {
+            self.drive
+                .as_ref()
+                .filter(|drive| drive.has_wheel(wheel))
+                .map(DriveConfig::counts_per_metre)
+        });
+        match unit {
+            VelocityUnit::CountsPerSecond => Some(velocity),
+            VelocityUnit::MetresPerSecond => counts_per_metre.map(|cpm| velocity * cpm),
+        }
+    }
+    /// Largest change in speed per second the motor is allowed, if it's ramped
+    pub fn max_acceleration(&self, motor: &str) -> Option<f32> {
+        self.pad
+            .motors
+            .get(motor)
+            .map(PadMotorConfig::max_acceleration)
+            .or_else(|| self.system.motors.get(motor).map(HBridgeConfig::max_acceleration))
+            .flatten()
+    }
+    pub fn motor_names(&self) -> Vec<String> {
+        self.pad
+            .motors
+            .keys()
+            .chain(self.system.motors.keys())
+            .cloned()
+            .collect()
+    }
+    /// Rejects devices the hardware doesn't have, so a typo fails at startup rather than on use
+    pub fn check(&self) -> Result<()> {
+        for (name, encoder) in &self.pad.encoders {
+            if encoder.port() as usize >= ENCODER_COUNT {
+                bail!("Encoder {} is on port {}, the PAD only has {} ports", name, encoder.port(), ENCODER_COUNT);
+            }
+        }
+        Ok(())
+    }
 }
 pub fn load_config() -> Config {
     let config_file_path = xdg::BaseDirectories::with_prefix("spine")
@@ -64,6 +602,39 @@ pub fn load_config() -> Config {
     buf_reader.read_to_string(&mut contents).unwrap();
 
     let config: Config = toml::from_str(&contents).unwrap();
+    config.check().unwrap();
     info!("{:#?}", config);
     config
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(encoders: &str) -> Config {
+        toml::from_str(&format!(
+            r#"
+            [pad.motors]
+            [pad.encoders]
+            {}
+            [pad.servos]
+            [system]
+            pca9685_path = "/dev/i2c-1"
+            [system.motors]
+            [system.limit_switches]
+            [system.status_leds]
+            [system.servos]
+            "#,
+            encoders
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn encoders_past_the_pad_ports_are_rejected() {
+        let last = ENCODER_COUNT - 1;
+        assert!(config(&format!("arm_base = {}", last)).check().is_ok());
+        let past = ENCODER_COUNT;
+        assert!(config(&format!("arm_base = {{ port = {}, scale = 1.0, unit = \"rad\" }}", past)).check().is_err());
+    }
+}
@@ -0,0 +1,289 @@
+use crate::config::{Config, JointConfig, WheelConfig};
+use crate::server::{handle_request, Channels, HardwareRequest, HardwareResponse};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::time::{Duration, Instant};
+use tracing::{debug, trace, warn};
+
+pub use spine_client::VelocityUnit;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(default)]
+pub struct PidConfig {
+    pub kp: f32,
+    pub ki: f32,
+    pub kd: f32,
+    /// Largest magnitude of the integral term's contribution to the output
+    pub integral_limit: f32,
+    /// Largest magnitude of the output, as a motor speed
+    pub output_limit: f32,
+}
+impl Default for PidConfig {
+    fn default() -> Self {
+        Self {
+            kp: 0.0,
+            ki: 0.0,
+            kd: 0.0,
+            integral_limit: 1.0,
+            output_limit: 1.0,
+        }
+    }
+}
+
+pub struct Pid {
+    config: PidConfig,
+    integral: f32,
+    last_error: Option<f32>,
+}
+impl Pid {
+    pub fn new(config: PidConfig) -> Self {
+        Self {
+            config,
+            integral: 0.0,
+            last_error: None,
+        }
+    }
+    pub fn reset(&mut self) {
+        self.integral = 0.0;
+        self.last_error = None;
+    }
+    /// Forgets the last error, so a step in the target doesn't kick the derivative term
+    pub fn target_changed(&mut self) {
+        self.last_error = None;
+    }
+    pub fn update(&mut self, error: f32, dt: f32) -> f32 {
+        let PidConfig {
+            kp,
+            ki,
+            kd,
+            integral_limit,
+            output_limit,
+        } = self.config;
+        if ki != 0.0 {
+            let limit = (integral_limit / ki).abs();
+            self.integral = (self.integral + error * dt).clamp(-limit, limit);
+        }
+        let derivative = match self.last_error {
+            Some(last_error) if dt > 0.0 => (error - last_error) / dt,
+            _ => 0.0,
+        };
+        self.last_error = Some(error);
+        (kp * error + ki * self.integral + kd * derivative).clamp(-output_limit, output_limit)
+    }
+}
+
+/// Holds `joint` at the target in counts sent on `target`, by reading its encoder and driving its
+/// motor at a fixed rate. A target of `None` releases the joint, stopping its motor.
+pub async fn control_joint(
+    config: Arc<Config>,
+    joint: String,
+    mut target: watch::Receiver<Option<i64>>,
+    mut channels: Channels,
+) {
+    let JointConfig {
+        encoder,
+        motor,
+        pid,
+        rate_hz,
+    } = config.joints[&joint].clone();
+    let period = Duration::from_secs_f32(1.0 / rate_hz.max(0.1));
+    let mut pid = Pid::new(pid);
+    let mut interval = tokio::time::interval(period);
+    loop {
+        let Some(counts) = *target.borrow() else {
+            debug!("Joint {} released", joint);
+            pid.reset();
+            let stop = HardwareRequest::MotorSet {
+                motor: motor.clone(),
+                speed: 0.0,
+            };
+            handle_request(&config, stop, &mut channels).await;
+            if target.changed().await.is_err() {
+                break;
+            }
+            continue;
+        };
+        tokio::select! {
+            changed = target.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+                debug!("Joint {} target set to {:?}", joint, *target.borrow());
+                pid.target_changed();
+            }
+            _ = interval.tick() => {
+                // Motor requests are refused meanwhile, the integral mustn't wind up
+                if channels.status.borrow().motors_inhibited() {
+                    pid.reset();
+                    continue;
+                }
+                let read = HardwareRequest::EncoderRead { encoder: encoder.clone() };
+                let position = match channels.backends.dispatch(read).await {
+                    HardwareResponse::EncoderValue(position) => position,
+                    response => {
+                        warn!("Could not read encoder {} for joint {}: {:?}", encoder, joint, response);
+                        continue;
+                    }
+                };
+                let error = (counts - position) as f32;
+                let speed = pid.update(error, period.as_secs_f32());
+                trace!("Joint {} at {}, error {}, speed {}", joint, position, error, speed);
+                let write = HardwareRequest::MotorSet { motor: motor.clone(), speed };
+                handle_request(&config, write, &mut channels).await;
+            }
+        }
+    }
+}
+
+/// Keeps `wheel` at the velocity in counts per second sent on `target`, with feedforward and
+/// PID on the change in its encoder readings. A target of `None` releases the wheel, stopping its
+/// motor.
+pub async fn control_wheel(
+    config: Arc<Config>,
+    wheel: String,
+    mut target: watch::Receiver<Option<f32>>,
+    mut channels: Channels,
+) {
+    let WheelConfig {
+        encoder,
+        motor,
+        kf,
+        pid,
+        rate_hz,
+        ..
+    } = config.wheels[&wheel].clone();
+    let period = Duration::from_secs_f32(1.0 / rate_hz.max(0.1));
+    let mut pid = Pid::new(pid);
+    let mut interval = tokio::time::interval(period);
+    let mut last_reading: Option<(i64, Instant)> = None;
+    loop {
+        let Some(velocity) = *target.borrow() else {
+            debug!("Wheel {} released", wheel);
+            pid.reset();
+            last_reading = None;
+            let stop = HardwareRequest::MotorSet {
+                motor: motor.clone(),
+                speed: 0.0,
+            };
+            handle_request(&config, stop, &mut channels).await;
+            if target.changed().await.is_err() {
+                break;
+            }
+            continue;
+        };
+        tokio::select! {
+            changed = target.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+                debug!("Wheel {} target set to {:?}counts/s", wheel, *target.borrow());
+                pid.target_changed();
+            }
+            _ = interval.tick() => {
+                // Motor requests are refused meanwhile, the integral mustn't wind up
+                if channels.status.borrow().motors_inhibited() {
+                    pid.reset();
+                    last_reading = None;
+                    continue;
+                }
+                let read = HardwareRequest::EncoderRead { encoder: encoder.clone() };
+                let position = match channels.backends.dispatch(read).await {
+                    HardwareResponse::EncoderValue(position) => position,
+                    response => {
+                        warn!("Could not read encoder {} for wheel {}: {:?}", encoder, wheel, response);
+                        continue;
+                    }
+                };
+                let now = Instant::now();
+                let Some((last_position, last_time)) = last_reading.replace((position, now)) else {
+                    continue;
+                };
+                let dt = (now - last_time).as_secs_f32();
+                if dt <= 0.0 {
+                    continue;
+                }
+                let measured = (position - last_position) as f32 / dt;
+                let error = velocity - measured;
+                let speed = (kf * velocity + pid.update(error, dt))
+                    .clamp(-pid.config.output_limit, pid.config.output_limit);
+                trace!("Wheel {} at {}counts/s, error {}, speed {}", wheel, measured, error, speed);
+                let write = HardwareRequest::MotorSet { motor: motor.clone(), speed };
+                handle_request(&config, write, &mut channels).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pid(kp: f32, ki: f32, kd: f32) -> Pid {
+        Pid::new(PidConfig {
+            kp,
+            ki,
+            kd,
+            ..Default::default()
+        })
+    }
+
+    fn pid_with_room() -> Pid {
+        Pid::new(PidConfig {
+            ki: 0.5,
+            kd: 0.1,
+            integral_limit: 10.0,
+            output_limit: 10.0,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn proportional_output_is_clamped() {
+        let mut pid = pid(0.01, 0.0, 0.0);
+        assert_eq!(pid.update(50.0, 0.1), 0.5);
+        assert_eq!(pid.update(500.0, 0.1), 1.0);
+        assert_eq!(pid.update(-500.0, 0.1), -1.0);
+    }
+
+    #[test]
+    fn integral_is_clamped_to_its_limit() {
+        let mut pid = Pid::new(PidConfig {
+            ki: 0.5,
+            integral_limit: 0.2,
+            ..Default::default()
+        });
+        assert_eq!(pid.update(0.2, 1.0), 0.1);
+        for _ in 0..100 {
+            pid.update(10.0, 1.0);
+        }
+        assert_eq!(pid.update(10.0, 1.0), 0.2);
+        // Unwinds from the limit rather than from everything accumulated
+        assert_eq!(pid.update(-0.2, 1.0), 0.1);
+    }
+
+    #[test]
+    fn derivative_needs_a_previous_error() {
+        let mut pid = pid(0.0, 0.0, 0.1);
+        assert_eq!(pid.update(5.0, 0.5), 0.0);
+        assert_eq!(pid.update(6.0, 0.5), 0.2);
+        assert_eq!(pid.update(6.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn target_change_does_not_kick_the_derivative() {
+        let mut pid = pid_with_room();
+        pid.update(1.0, 1.0);
+        pid.target_changed();
+        // Only the integral, kept across the change, contributes
+        assert_eq!(pid.update(3.0, 1.0), 2.0);
+    }
+
+    #[test]
+    fn reset_forgets_the_integral() {
+        let mut pid = pid_with_room();
+        pid.update(4.0, 1.0);
+        pid.reset();
+        assert_eq!(pid.update(2.0, 1.0), 1.0);
+    }
+}
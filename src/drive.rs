@@ -0,0 +1,117 @@
+use crate::config::{Config, DriveConfig};
+use crate::control::VelocityUnit;
+use crate::server::{Channels, HardwareRequest, HardwareResponse};
+use std::f32::consts::PI;
+use std::sync::Arc;
+use tokio::sync::Notify;
+use tokio::time::{Duration, Instant};
+use tracing::{debug, trace, warn};
+
+pub use spine_client::{Odometry, ODOMETRY_TOPIC};
+
+/// Requests driving the left and right sides for a twist, in m/s and rad/s. Sides with velocity
+/// controlled wheels are commanded through them, otherwise their motors are driven open loop.
+pub fn twist_requests(drive: &DriveConfig, linear: f32, angular: f32) -> Vec<HardwareRequest> {
+    let left = linear - angular * drive.track_width / 2.0;
+    let right = linear + angular * drive.track_width / 2.0;
+    debug!("Driving left at {}m/s and right at {}m/s", left, right);
+    [
+        (left, &drive.left_wheels, &drive.left_motors),
+        (right, &drive.right_wheels, &drive.right_motors),
+    ]
+    .into_iter()
+    .flat_map(|(velocity, wheels, motors)| -> Vec<HardwareRequest> {
+        if wheels.is_empty() {
+            motors
+                .iter()
+                .map(|motor| HardwareRequest::MotorSet {
+                    motor: motor.clone(),
+                    speed: velocity / drive.max_speed,
+                })
+                .collect()
+        } else {
+            wheels
+                .iter()
+                .map(|wheel| HardwareRequest::WheelSetVelocity {
+                    wheel: wheel.clone(),
+                    velocity,
+                    unit: VelocityUnit::MetresPerSecond,
+                })
+                .collect()
+        }
+    })
+    .collect()
+}
+
+/// Average of the encoders, `None` if any of them can't be read
+async fn read_side(channels: &Channels, encoders: &[String]) -> Option<f64> {
+    let mut total = 0.0;
+    for encoder in encoders {
+        let read = HardwareRequest::EncoderRead {
+            encoder: encoder.clone(),
+        };
+        match channels.backends.dispatch(read).await {
+            HardwareResponse::EncoderValue(counts) => total += counts as f64,
+            response => {
+                warn!("Could not read encoder {} for odometry: {:?}", encoder, response);
+                return None;
+            }
+        }
+    }
+    Some(total / encoders.len().max(1) as f64)
+}
+
+/// Integrates the drive encoders into odometry at a fixed rate, publishing it on `ODOMETRY_TOPIC`
+pub async fn integrate_odometry(config: Arc<Config>, channels: Channels, reset: Arc<Notify>) {
+    let Some(drive) = config.drive.clone() else {
+        return;
+    };
+    let metres_per_count = 1.0 / drive.counts_per_metre();
+    let DriveConfig {
+        track_width,
+        left_encoders,
+        right_encoders,
+        rate_hz,
+        ..
+    } = drive;
+    let mut odometry = Odometry::default();
+    let mut last: Option<(f64, f64, Instant)> = None;
+    let mut interval = tokio::time::interval(Duration::from_secs_f32(1.0 / rate_hz.max(0.1)));
+    loop {
+        tokio::select! {
+            _ = reset.notified() => {
+                debug!("Resetting odometry");
+                odometry = Odometry::default();
+            }
+            _ = interval.tick() => {
+                let (Some(left), Some(right)) = (
+                    read_side(&channels, &left_encoders).await,
+                    read_side(&channels, &right_encoders).await,
+                ) else {
+                    last = None;
+                    continue;
+                };
+                let now = Instant::now();
+                if let Some((last_left, last_right, last_time)) = last.replace((left, right, now)) {
+                    let dt = (now - last_time).as_secs_f32();
+                    let left = (left - last_left) as f32 * metres_per_count;
+                    let right = (right - last_right) as f32 * metres_per_count;
+                    let distance = (left + right) / 2.0;
+                    let turn = (right - left) / track_width;
+                    let heading = odometry.heading + turn / 2.0;
+                    odometry.x += distance * heading.cos();
+                    odometry.y += distance * heading.sin();
+                    odometry.heading = (odometry.heading + turn + PI).rem_euclid(2.0 * PI) - PI;
+                    if dt > 0.0 {
+                        odometry.linear = distance / dt;
+                        odometry.angular = turn / dt;
+                    }
+                    trace!("Odometry: {:?}", odometry);
+                }
+                channels
+                    .telemetry
+                    .publish(ODOMETRY_TOPIC, HardwareResponse::Odometry(odometry));
+            }
+        }
+    }
+}
@@ -0,0 +1,81 @@
+/// Counts of a PAD encoder, unwrapped from the PAD's i32 into an i64 and zeroed on the host
+#[derive(Debug, Default, Clone, Copy)]
+pub struct EncoderTracker {
+    last_raw: i32,
+    accumulated: i64,
+    offset: i64,
+    /// The next reading is where counting carries on from, rather than a change since the last one
+    rebase: bool,
+}
+impl EncoderTracker {
+    /// Takes a reading from the PAD, assuming the encoder moved less than half the i32 range since
+    /// the last one
+    pub fn update(&mut self, raw: i32) {
+        if !std::mem::take(&mut self.rebase) {
+            self.accumulated += raw.wrapping_sub(self.last_raw) as i64;
+        }
+        self.last_raw = raw;
+    }
+    pub fn value(&self) -> i64 {
+        self.accumulated - self.offset
+    }
+    pub fn zero(&mut self) {
+        self.offset = self.accumulated;
+    }
+    /// Keeps the value and its zero across a reconnect to a PAD that may have been reset, as
+    /// whatever it moved while disconnected can't be known
+    pub fn rebase(&mut self) {
+        self.rebase = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker_at(readings: &[i32]) -> EncoderTracker {
+        let mut tracker = EncoderTracker::default();
+        readings.iter().for_each(|&raw| tracker.update(raw));
+        tracker
+    }
+
+    #[test]
+    fn first_reading_counts_from_zero() {
+        assert_eq!(tracker_at(&[1234]).value(), 1234);
+        assert_eq!(tracker_at(&[-20, 30]).value(), 30);
+    }
+
+    #[test]
+    fn unwraps_past_i32_max() {
+        let tracker = tracker_at(&[i32::MAX - 10, i32::MIN + 9]);
+        assert_eq!(tracker.value(), i32::MAX as i64 + 10);
+    }
+
+    #[test]
+    fn unwraps_past_i32_min() {
+        let tracker = tracker_at(&[i32::MIN + 5, i32::MAX - 4]);
+        assert_eq!(tracker.value(), i32::MIN as i64 - 5);
+    }
+
+    #[test]
+    fn zero_offsets_later_readings() {
+        let mut tracker = tracker_at(&[100]);
+        tracker.zero();
+        assert_eq!(tracker.value(), 0);
+        tracker.update(70);
+        assert_eq!(tracker.value(), -30);
+    }
+
+    #[test]
+    fn rebase_keeps_the_value_and_zero() {
+        let mut tracker = tracker_at(&[100]);
+        tracker.zero();
+        tracker.update(150);
+        tracker.rebase();
+        // The PAD restarted counting from zero
+        tracker.update(3);
+        assert_eq!(tracker.value(), 50);
+        tracker.update(13);
+        assert_eq!(tracker.value(), 60);
+    }
+}
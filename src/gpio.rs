@@ -0,0 +1,147 @@
+use eyre::{eyre, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use sysfs_gpio::Pin;
+use tracing::debug;
+
+pub use sysfs_gpio::Direction;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GpioBackend {
+    /// The host's GPIOs through /sys/class/gpio
+    #[default]
+    Sysfs,
+    /// Pins kept in memory, for running spine off the rover
+    Mock,
+}
+
+/// Readings of a mock input, either constant or returned in turn and repeated
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum MockInput {
+    Constant(bool),
+    Sequence(Vec<bool>),
+}
+
+#[derive(Debug, Default)]
+pub struct MockPins {
+    values: HashMap<u64, u8>,
+    /// Script and position in it of every scripted input
+    scripts: HashMap<u64, (Vec<bool>, usize)>,
+}
+
+/// Where pins come from, shared by every pin it makes
+#[derive(Debug, Clone)]
+pub enum Gpio {
+    Sysfs,
+    Mock(Arc<Mutex<MockPins>>),
+}
+impl Gpio {
+    pub fn new(backend: GpioBackend) -> Self {
+        match backend {
+            GpioBackend::Sysfs => Self::Sysfs,
+            GpioBackend::Mock => Self::Mock(Default::default()),
+        }
+    }
+    pub fn pin(&self, number: u64) -> GpioPin {
+        match self {
+            Self::Sysfs => GpioPin::Sysfs(Pin::new(number)),
+            Self::Mock(pins) => GpioPin::Mock {
+                number,
+                pins: pins.clone(),
+            },
+        }
+    }
+    /// Makes the mock input `number` read `input`, does nothing on real GPIOs
+    pub fn script_input(&self, number: u64, input: &MockInput) {
+        if let Self::Mock(pins) = self {
+            let script = match input {
+                MockInput::Constant(value) => vec![*value],
+                MockInput::Sequence(values) => values.clone(),
+            };
+            pins.lock().unwrap().scripts.insert(number, (script, 0));
+        }
+    }
+}
+
+/// A GPIO, either a sysfs pin or one recorded in memory
+#[derive(Debug, Clone)]
+pub enum GpioPin {
+    Sysfs(Pin),
+    Mock { number: u64, pins: Arc<Mutex<MockPins>> },
+}
+impl GpioPin {
+    pub fn number(&self) -> u64 {
+        match self {
+            Self::Sysfs(pin) => pin.get_pin_num(),
+            Self::Mock { number, .. } => *number,
+        }
+    }
+    pub fn export(&self) -> Result<()> {
+        match self {
+            Self::Sysfs(pin) => Ok(pin.export()?),
+            Self::Mock { .. } => Ok(()),
+        }
+    }
+    pub fn set_direction(&self, direction: Direction) -> Result<()> {
+        match self {
+            Self::Sysfs(pin) => Ok(pin.set_direction(direction)?),
+            Self::Mock { number, .. } => {
+                debug!("Mock GPIO {} set to {:?}", number, direction);
+                Ok(())
+            }
+        }
+    }
+    pub fn set_value(&self, value: u8) -> Result<()> {
+        match self {
+            Self::Sysfs(pin) => Ok(pin.set_value(value)?),
+            Self::Mock { number, pins } => {
+                let previous = pins.lock().unwrap().values.insert(*number, value);
+                if previous != Some(value) {
+                    debug!("Mock GPIO {} written {}", number, value);
+                }
+                Ok(())
+            }
+        }
+    }
+    /// Reads the pin, mock inputs return their script if they have one and otherwise their last value
+    pub fn get_value(&self) -> Result<u8> {
+        match self {
+            Self::Sysfs(pin) => Ok(pin.get_value()?),
+            Self::Mock { number, pins } => {
+                let mut pins = pins.lock().unwrap();
+                if let Some((script, position)) = pins.scripts.get_mut(number) {
+                    if let Some(&value) = script.get(*position % script.len().max(1)) {
+                        *position += 1;
+                        return Ok(value as u8);
+                    }
+                }
+                Ok(pins.values.get(number).copied().unwrap_or(0))
+            }
+        }
+    }
+    /// The level the pin was last driven to, without moving a mock input along its script
+    pub fn read_output(&self) -> Result<u8> {
+        match self {
+            Self::Sysfs(pin) => Ok(pin.get_value()?),
+            Self::Mock { number, pins } => {
+                Ok(pins.lock().unwrap().values.get(number).copied().unwrap_or(0))
+            }
+        }
+    }
+    /// Drives a mock input as if from outside, replacing any script it had
+    pub fn set_input(&self, value: u8) -> Result<()> {
+        match self {
+            Self::Sysfs(_) => Err(eyre!("Only mock GPIO inputs can be set")),
+            Self::Mock { number, pins } => {
+                let mut pins = pins.lock().unwrap();
+                pins.scripts.remove(number);
+                pins.values.insert(*number, value);
+                debug!("Mock GPIO {} input set to {}", number, value);
+                Ok(())
+            }
+        }
+    }
+}
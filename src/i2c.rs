@@ -0,0 +1,264 @@
+use crate::config::{Config, I2cDeviceConfig};
+use crate::server::{Channels, HardwareResponse};
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+use eyre::{eyre, Result};
+use linux_embedded_hal::I2cdev;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::time::Duration;
+use tracing::{error, info, trace, warn};
+
+pub use spine_client::Readings;
+
+/// Chip on the other end of an I2C address, and how to read it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "driver", rename_all = "snake_case")]
+pub enum I2cDriver {
+    /// 4 channel ADC, read single ended in volts
+    Ads1115 {
+        #[serde(default = "default_ads1115_channels")]
+        channels: Vec<u8>,
+        /// Input range in volts, one of 6.144, 4.096, 2.048, 1.024, 0.512 or 0.256
+        #[serde(default = "default_ads1115_full_scale")]
+        full_scale: f32,
+    },
+    /// Temperature sensor
+    Tmp102,
+    /// Current and bus voltage monitors
+    Ina219 {
+        shunt_ohms: f32,
+    },
+    Ina226 {
+        shunt_ohms: f32,
+    },
+}
+fn default_ads1115_channels() -> Vec<u8> {
+    vec![0, 1, 2, 3]
+}
+fn default_ads1115_full_scale() -> f32 {
+    4.096
+}
+
+/// Reads `buf.len()` bytes starting at `register`
+pub fn read_block(dev: &mut I2cdev, address: u8, register: u8, buf: &mut [u8]) -> Result<()> {
+    dev.write_read(address, &[register], buf)
+        .map_err(|e| {
+            eyre!(
+                "Error reading register {:#x} of {:#x}: {}",
+                register,
+                address,
+                e
+            )
+        })
+}
+fn read_register(dev: &mut I2cdev, address: u8, register: u8) -> Result<u16> {
+    let mut buf = [0u8; 2];
+    read_block(dev, address, register, &mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+fn write_block(dev: &mut I2cdev, address: u8, register: u8, values: &[u8]) -> Result<()> {
+    let bytes: Vec<u8> = std::iter::once(register).chain(values.iter().copied()).collect();
+    dev.write(address, &bytes).map_err(|e| {
+        eyre!(
+            "Error writing register {:#x} of {:#x}: {}",
+            register,
+            address,
+            e
+        )
+    })
+}
+fn write_register(dev: &mut I2cdev, address: u8, register: u8, value: u16) -> Result<()> {
+    write_block(dev, address, register, &value.to_be_bytes())
+}
+pub fn write_byte(dev: &mut I2cdev, address: u8, register: u8, value: u8) -> Result<()> {
+    write_block(dev, address, register, &[value])
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Ina2xxKind {
+    Ina219,
+    Ina226,
+}
+/// INA219/INA226 current and bus voltage monitor, read without calibrating the chip
+pub struct Ina2xx {
+    dev: I2cdev,
+    kind: Ina2xxKind,
+    address: u8,
+    shunt_ohms: f32,
+}
+impl Ina2xx {
+    const SHUNT_VOLTAGE: u8 = 0x01;
+    const BUS_VOLTAGE: u8 = 0x02;
+
+    pub fn new(bus: &str, kind: Ina2xxKind, address: u8, shunt_ohms: f32) -> Result<Self> {
+        Ok(Self {
+            dev: I2cdev::new(bus)?,
+            kind,
+            address,
+            shunt_ohms,
+        })
+    }
+    pub fn bus_voltage(&mut self) -> Result<f32> {
+        let raw = read_register(&mut self.dev, self.address, Self::BUS_VOLTAGE)?;
+        Ok(match self.kind {
+            Ina2xxKind::Ina219 => (raw >> 3) as f32 * 0.004,
+            Ina2xxKind::Ina226 => raw as f32 * 0.00125,
+        })
+    }
+    pub fn current(&mut self) -> Result<f32> {
+        let raw = read_register(&mut self.dev, self.address, Self::SHUNT_VOLTAGE)? as i16;
+        let shunt_voltage = match self.kind {
+            Ina2xxKind::Ina219 => raw as f32 * 0.000_01,
+            Ina2xxKind::Ina226 => raw as f32 * 0.000_002_5,
+        };
+        Ok(shunt_voltage / self.shunt_ohms)
+    }
+}
+
+/// ADS1115 used in single-shot mode, one conversion per channel per read
+pub struct Ads1115 {
+    dev: I2cdev,
+    address: u8,
+    channels: Vec<u8>,
+    full_scale: f32,
+    pga: u16,
+}
+impl Ads1115 {
+    const CONVERSION: u8 = 0x00;
+    const CONFIG: u8 = 0x01;
+    /// 128 samples per second
+    const CONVERSION_TIME: Duration = Duration::from_millis(9);
+    const FULL_SCALES: [f32; 6] = [6.144, 4.096, 2.048, 1.024, 0.512, 0.256];
+
+    pub fn new(bus: &str, address: u8, channels: Vec<u8>, full_scale: f32) -> Result<Self> {
+        let pga = Self::FULL_SCALES
+            .iter()
+            .position(|fs| (fs - full_scale).abs() < 0.001)
+            .ok_or_else(|| eyre!("ADS1115 has no {}V range", full_scale))?;
+        if let Some(channel) = channels.iter().find(|&&channel| channel > 3) {
+            return Err(eyre!("ADS1115 has no channel {}", channel));
+        }
+        Ok(Self {
+            dev: I2cdev::new(bus)?,
+            address,
+            channels,
+            full_scale,
+            pga: pga as u16,
+        })
+    }
+    async fn read_channel(&mut self, channel: u8) -> Result<f64> {
+        // Start a single conversion of AINx against GND, with the comparator disabled
+        let config =
+            1 << 15 | (0b100 | channel as u16) << 12 | self.pga << 9 | 1 << 8 | 0b100 << 5 | 0b11;
+        write_register(&mut self.dev, self.address, Self::CONFIG, config)?;
+        tokio::time::sleep(Self::CONVERSION_TIME).await;
+        let raw = read_register(&mut self.dev, self.address, Self::CONVERSION)? as i16;
+        Ok(raw as f64 * self.full_scale as f64 / 32768.0)
+    }
+    pub async fn read(&mut self) -> Result<Readings> {
+        let mut readings = Readings::new();
+        for channel in self.channels.clone() {
+            readings.insert(
+                format!("ain{}_v", channel),
+                self.read_channel(channel).await?,
+            );
+        }
+        Ok(readings)
+    }
+}
+
+pub struct Tmp102 {
+    dev: I2cdev,
+    address: u8,
+}
+impl Tmp102 {
+    const TEMPERATURE: u8 = 0x00;
+
+    pub fn new(bus: &str, address: u8) -> Result<Self> {
+        Ok(Self {
+            dev: I2cdev::new(bus)?,
+            address,
+        })
+    }
+    pub fn temperature(&mut self) -> Result<f64> {
+        // 12 bit two's complement, left aligned, in 1/16ths of a degree
+        let raw = read_register(&mut self.dev, self.address, Self::TEMPERATURE)? as i16;
+        Ok((raw >> 4) as f64 * 0.0625)
+    }
+}
+
+pub enum I2cDevice {
+    Ads1115(Ads1115),
+    Tmp102(Tmp102),
+    Ina2xx(Ina2xx),
+}
+impl I2cDevice {
+    pub fn from_config(config: &Config, device: &I2cDeviceConfig) -> Result<Self> {
+        let bus = device.bus.as_deref().unwrap_or(&config.system.pca9685_path);
+        Ok(match &device.driver {
+            I2cDriver::Ads1115 {
+                channels,
+                full_scale,
+            } => Self::Ads1115(Ads1115::new(
+                bus,
+                device.address,
+                channels.clone(),
+                *full_scale,
+            )?),
+            I2cDriver::Tmp102 => Self::Tmp102(Tmp102::new(bus, device.address)?),
+            I2cDriver::Ina219 { shunt_ohms } => Self::Ina2xx(Ina2xx::new(
+                bus,
+                Ina2xxKind::Ina219,
+                device.address,
+                *shunt_ohms,
+            )?),
+            I2cDriver::Ina226 { shunt_ohms } => Self::Ina2xx(Ina2xx::new(
+                bus,
+                Ina2xxKind::Ina226,
+                device.address,
+                *shunt_ohms,
+            )?),
+        })
+    }
+    pub async fn read(&mut self) -> Result<Readings> {
+        match self {
+            Self::Ads1115(ads) => ads.read().await,
+            Self::Tmp102(tmp) => Ok(Readings::from([(
+                "temperature_c".to_string(),
+                tmp.temperature()?,
+            )])),
+            Self::Ina2xx(ina) => Ok(Readings::from([
+                ("bus_voltage_v".to_string(), ina.bus_voltage()? as f64),
+                ("current_a".to_string(), ina.current()? as f64),
+            ])),
+        }
+    }
+}
+
+/// Reads `device` at its configured rate, publishing its readings on the topic of the same name
+pub async fn poll_device(config: Arc<Config>, name: String, channels: Channels) {
+    let device_config = &config.system.i2c[&name];
+    let mut device = match I2cDevice::from_config(&config, device_config) {
+        Ok(device) => device,
+        Err(e) => {
+            error!("Could not open I2C device {}: {}", name, e);
+            return;
+        }
+    };
+    info!("Polling I2C device {} at {}Hz", name, device_config.rate_hz);
+    let mut interval = tokio::time::interval(Duration::from_secs_f32(
+        1.0 / device_config.rate_hz.max(0.01),
+    ));
+    loop {
+        interval.tick().await;
+        match device.read().await {
+            Ok(readings) => {
+                trace!("{}: {:?}", name, readings);
+                channels
+                    .telemetry
+                    .publish(&name, HardwareResponse::Readings(readings));
+            }
+            Err(e) => warn!("Could not read I2C device {}: {}", name, e),
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use crate::config::{Config, ImuConfig};
+use crate::i2c::{read_block, write_byte};
+use crate::server::{stop_all_motors, Channels, HardwareResponse};
+use eyre::Result;
+use linux_embedded_hal::I2cdev;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::time::{Duration, Instant};
+use tracing::{error, info, trace, warn};
+
+pub use spine_client::{ImuState, IMU_TOPIC};
+
+const GRAVITY: f32 = 9.80665;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "driver", rename_all = "snake_case")]
+pub enum ImuDriver {
+    /// Raw accelerometer and gyro, fused by spine with a complementary filter
+    Mpu6050 {
+        /// Weight of the integrated gyro against the accelerometer, closer to 1.0 is smoother
+        #[serde(default = "default_filter_alpha")]
+        filter_alpha: f32,
+    },
+    /// Orientation from the chip's own fusion
+    Bno055,
+}
+fn default_filter_alpha() -> f32 {
+    0.98
+}
+impl ImuDriver {
+    pub fn default_address(&self) -> u8 {
+        match self {
+            Self::Mpu6050 { .. } => 0x68,
+            Self::Bno055 => 0x28,
+        }
+    }
+}
+
+/// MPU6050 at its default ranges of ±2g and ±250°/s
+pub struct Mpu6050 {
+    dev: I2cdev,
+    address: u8,
+    filter_alpha: f32,
+    state: Option<ImuState>,
+    last_read: Instant,
+}
+impl Mpu6050 {
+    const PWR_MGMT_1: u8 = 0x6b;
+    const ACCEL_XOUT_H: u8 = 0x3b;
+    const ACCEL_LSB_PER_G: f32 = 16384.0;
+    const GYRO_LSB_PER_DPS: f32 = 131.0;
+
+    pub fn new(bus: &str, address: u8, filter_alpha: f32) -> Result<Self> {
+        let mut dev = I2cdev::new(bus)?;
+        // Wake up from sleep, clocked from the internal oscillator
+        write_byte(&mut dev, address, Self::PWR_MGMT_1, 0)?;
+        Ok(Self {
+            dev,
+            address,
+            filter_alpha,
+            state: None,
+            last_read: Instant::now(),
+        })
+    }
+    pub fn read(&mut self) -> Result<ImuState> {
+        // Accelerometer, temperature and gyro, big endian
+        let mut buf = [0u8; 14];
+        read_block(&mut self.dev, self.address, Self::ACCEL_XOUT_H, &mut buf)?;
+        let word = |i: usize| i16::from_be_bytes([buf[i], buf[i + 1]]) as f32;
+        let accel = [0, 2, 4].map(|i| word(i) / Self::ACCEL_LSB_PER_G * GRAVITY);
+        let gyro = [8, 10, 12].map(|i| word(i) / Self::GYRO_LSB_PER_DPS);
+
+        let now = Instant::now();
+        let dt = (now - self.last_read).as_secs_f32();
+        self.last_read = now;
+        let [ax, ay, az] = accel;
+        let accel_roll = ay.atan2(az).to_degrees();
+        let accel_pitch = (-ax).atan2((ay * ay + az * az).sqrt()).to_degrees();
+        let alpha = self.filter_alpha;
+        let state = match self.state {
+            None => ImuState {
+                roll: accel_roll,
+                pitch: accel_pitch,
+                yaw: 0.0,
+                accel,
+                gyro,
+            },
+            Some(last) => ImuState {
+                roll: alpha * (last.roll + gyro[0] * dt) + (1.0 - alpha) * accel_roll,
+                pitch: alpha * (last.pitch + gyro[1] * dt) + (1.0 - alpha) * accel_pitch,
+                // Nothing to correct yaw with, so it drifts
+                yaw: (last.yaw + gyro[2] * dt).rem_euclid(360.0),
+                accel,
+                gyro,
+            },
+        };
+        self.state = Some(state);
+        Ok(state)
+    }
+}
+
+/// BNO055 in NDOF mode, fusing accelerometer, gyro and magnetometer on the chip
+pub struct Bno055 {
+    dev: I2cdev,
+    address: u8,
+}
+impl Bno055 {
+    const ACC_DATA_X_LSB: u8 = 0x08;
+    const OPR_MODE: u8 = 0x3d;
+    const OPR_MODE_NDOF: u8 = 0x0c;
+    const ACCEL_LSB_PER_MS2: f32 = 100.0;
+    const GYRO_LSB_PER_DPS: f32 = 16.0;
+    const EULER_LSB_PER_DEG: f32 = 16.0;
+
+    pub async fn new(bus: &str, address: u8) -> Result<Self> {
+        let mut dev = I2cdev::new(bus)?;
+        write_byte(&mut dev, address, Self::OPR_MODE, Self::OPR_MODE_NDOF)?;
+        // Switching out of config mode takes 7ms
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        Ok(Self { dev, address })
+    }
+    pub fn read(&mut self) -> Result<ImuState> {
+        // Accelerometer, magnetometer, gyro and euler angles, little endian
+        let mut buf = [0u8; 24];
+        read_block(&mut self.dev, self.address, Self::ACC_DATA_X_LSB, &mut buf)?;
+        let word = |i: usize| i16::from_le_bytes([buf[i], buf[i + 1]]) as f32;
+        Ok(ImuState {
+            yaw: word(18) / Self::EULER_LSB_PER_DEG,
+            roll: word(20) / Self::EULER_LSB_PER_DEG,
+            pitch: word(22) / Self::EULER_LSB_PER_DEG,
+            accel: [0, 2, 4].map(|i| word(i) / Self::ACCEL_LSB_PER_MS2),
+            gyro: [12, 14, 16].map(|i| word(i) / Self::GYRO_LSB_PER_DPS),
+        })
+    }
+}
+
+pub enum Imu {
+    Mpu6050(Mpu6050),
+    Bno055(Bno055),
+}
+impl Imu {
+    pub async fn from_config(config: &Config, imu: &ImuConfig) -> Result<Self> {
+        let bus = imu.bus.as_deref().unwrap_or(&config.system.pca9685_path);
+        let address = imu.address.unwrap_or_else(|| imu.driver.default_address());
+        Ok(match imu.driver {
+            ImuDriver::Mpu6050 { filter_alpha } => {
+                Self::Mpu6050(Mpu6050::new(bus, address, filter_alpha)?)
+            }
+            ImuDriver::Bno055 => Self::Bno055(Bno055::new(bus, address).await?),
+        })
+    }
+    pub fn read(&mut self) -> Result<ImuState> {
+        match self {
+            Self::Mpu6050(mpu) => mpu.read(),
+            Self::Bno055(bno) => bno.read(),
+        }
+    }
+}
+
+/// Reads the IMU at a fixed rate, publishing its state on `IMU_TOPIC` and stopping the motors
+/// while the rover is tilted past `max_tilt_deg`.
+pub async fn run_imu(config: Arc<Config>, mut channels: Channels) {
+    let Some(imu_config) = config.imu.clone() else {
+        return;
+    };
+    let mut imu = match Imu::from_config(&config, &imu_config).await {
+        Ok(imu) => imu,
+        Err(e) => {
+            error!("Could not set up IMU: {}", e);
+            return;
+        }
+    };
+    info!("Reading IMU at {}Hz", imu_config.rate_hz);
+    let mut interval =
+        tokio::time::interval(Duration::from_secs_f32(1.0 / imu_config.rate_hz.max(0.1)));
+    loop {
+        interval.tick().await;
+        let state = match imu.read() {
+            Ok(state) => state,
+            Err(e) => {
+                warn!("Could not read IMU: {}", e);
+                continue;
+            }
+        };
+        trace!("IMU: {:?}", state);
+        channels
+            .telemetry
+            .publish(IMU_TOPIC, HardwareResponse::Imu(state));
+
+        let Some(max_tilt) = imu_config.max_tilt_deg else {
+            continue;
+        };
+        let tilt = state.tilt();
+        let was_tilted = channels.status.borrow().tilted;
+        let tilted = if was_tilted {
+            tilt > max_tilt - imu_config.tilt_hysteresis_deg
+        } else {
+            tilt > max_tilt
+        };
+        if tilted != was_tilted {
+            channels.status.send_modify(|status| status.tilted = tilted);
+            if tilted {
+                error!("Tilted {}°, past {}°, stopping all motors", tilt, max_tilt);
+                stop_all_motors(&config, &mut channels).await;
+            } else {
+                info!("Tilt back to {}°, motors allowed again", tilt);
+            }
+        }
+    }
+}
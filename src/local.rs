@@ -1,113 +1,138 @@
-use crate::config::Config;
-use crate::server::HardwareRequest;
-use eyre::{Error, Result};
-use std::collections::HashMap;
-use sysfs_gpio::{Direction, Pin};
-use tokio::sync::oneshot;
-use tokio::time::{sleep, Duration};
+use crate::backend::{Backend, DeviceKind};
+use crate::config::{Config, HBridgeConfig};
+use crate::motor::command_to_speed;
+use crate::pwm::{pca9685_channel, PwmOutput};
+use crate::servo::microseconds_to_ticks;
+use crate::status::{self, Colour, LedPattern, StatusCommand, SystemStatus};
+use eyre::{eyre, Error, Result};
 use linux_embedded_hal::I2cdev;
 use pwm_pca9685 as pca9685;
 use pwm_pca9685::{Channel, Pca9685};
-use tracing::debug;
+use std::collections::{BTreeSet, HashMap};
+use crate::gpio::{Direction, Gpio, GpioPin};
+use tokio::sync::{mpsc, watch};
+use tokio::time::{sleep, Duration};
+use tracing::{debug, error, warn};
 
-type HBridgePinPair = [Pin; 2];
+type HBridgePinPair = [GpioPin; 2];
+struct HBridge {
+    pins: HBridgePinPair,
+    enable: Option<PwmOutput>,
+    threshold: f32,
+}
 pub struct LocalConnections {
-    limit_switches: HashMap<String, Pin>,
-    h_bridge: HashMap<String, HBridgePinPair>,
-    status_leds: HashMap<String, Pin>,
+    limit_switches: HashMap<String, GpioPin>,
+    h_bridge: HashMap<String, HBridge>,
+    status_leds: HashMap<String, GpioPin>,
+    status_indicator: Option<mpsc::Sender<StatusCommand>>,
     servos: HashMap<String, Channel>,
-    // pwm_device: Pca9685<I2cdev>,
-    pwm_freq: u32,
-    pwm_adc_max_value: u32,
-}
-
-#[derive(Debug)]
-pub struct LocalRequest {
-    pub body: HardwareRequest,
-    tx: tokio::sync::oneshot::Sender<LocalResponse>,
-}
-
-#[derive(Debug)]
-pub enum LocalResponse {
-    SwitchOn(bool),
-    Ok,
+    pwm_device: Option<Pca9685<I2cdev>>,
+    pwm_freq: u16,
+    pwm_adc_max_value: u16,
 }
 
 impl LocalConnections {
-    pub async fn from_config(config: &Config) -> Self {
+    pub async fn from_config(config: &Config, gpio: Gpio) -> Self {
         let mut config = config.system.clone();
-        let limit_switches: HashMap<String, Pin> = config
+        for (switch, input) in &config.mock_inputs {
+            match config.limit_switches.get(switch) {
+                Some(&pin) => gpio.script_input(pin, input),
+                None => warn!("No limit switch named {} to script", switch),
+            }
+        }
+        let limit_switches: HashMap<String, GpioPin> = config
             .limit_switches
             .drain()
-            .map(|(name, pin)| (name, Pin::new(pin as u64)))
+            .map(|(name, pin)| (name, gpio.pin(pin)))
             .collect();
-        let h_bridge: HashMap<String, HBridgePinPair> = config
+        let h_bridge: HashMap<String, HBridge> = config
             .motors
             .drain()
-            .map(|(name, pins)| (name, [Pin::new(pins[0]), Pin::new(pins[1])]))
+            .map(|(name, h_bridge)| {
+                let pins = h_bridge.pins();
+                let threshold = h_bridge.threshold();
+                let enable = match h_bridge {
+                    HBridgeConfig::Table {
+                        enable: Some(enable),
+                        ..
+                    } => PwmOutput::from_config(&enable, &gpio)
+                        .map_err(|e| error!("Could not set up enable pin for {}: {}", name, e))
+                        .ok(),
+                    _ => None,
+                };
+                let pins = [gpio.pin(pins[0]), gpio.pin(pins[1])];
+                (
+                    name,
+                    HBridge {
+                        pins,
+                        enable,
+                        threshold,
+                    },
+                )
+            })
             .collect();
-        let status_leds: HashMap<String, Pin> = config
+        let status_leds: HashMap<String, GpioPin> = config
             .status_leds
             .drain()
-            .map(|(name, pin)| (name, Pin::new(pin)))
+            .map(|(name, pin)| (name, gpio.pin(pin)))
             .collect();
-        // udev takes ~80ms to export the pins
-
-        limit_switches
-            .values()
-            .for_each(|pin| pin.export().unwrap());
-        h_bridge
-            .values()
-            .for_each(|pins| pins.iter().for_each(|pin| pin.export().unwrap()));
-        status_leds.values().for_each(|pin| pin.export().unwrap());
-        sleep(Duration::from_millis(100)).await;
-
-        // let dev = I2cdev::new(config.pca9685_path).unwrap();
-        // let mut pwm_device = Pca9685::new(dev, pca9685::Address::default()).unwrap();
-        // pwm_device.set_prescale(100).unwrap();
-        // pwm_device.enable().unwrap();
-        // pwm.set_all_on_off(&[0; 16], &[0; 16]).unwrap();
-
+        let pwm_device = Self::setup_pca9685(&config.pca9685_path, config.pwm_freq)
+            .map_err(|e| error!("Could not set up PCA9685 at {}: {}", config.pca9685_path, e))
+            .ok();
 
         let servos: HashMap<String, Channel> = config
             .servos
             .drain()
-            .map(|(name, channel)| match channel {
-                0 => (name, Channel::C0),
-                1 => (name, Channel::C1),
-                2 => (name, Channel::C2),
-                3 => (name, Channel::C3),
-                4 => (name, Channel::C4),
-                5 => (name, Channel::C5),
-                6 => (name, Channel::C6),
-                7 => (name, Channel::C7),
-                8 => (name, Channel::C8),
-                9 => (name, Channel::C9),
-                10 => (name, Channel::C10),
-                11 => (name, Channel::C11),
-                12 => (name, Channel::C12),
-                13 => (name, Channel::C13),
-                14 => (name, Channel::C14),
-                15 => (name, Channel::C15),
-                _ => panic!("Invalid servo channel"),
-            })
+            .map(|(name, servo)| (name, pca9685_channel(servo.channel()).unwrap()))
             .collect();
-        Self {
+        let connections = Self {
             limit_switches,
             h_bridge,
             status_leds,
-            // pwm_device,
+            status_indicator: None,
+            pwm_device,
             servos,
-            pwm_freq: 60,
+            pwm_freq: config.pwm_freq,
             pwm_adc_max_value: 4095,
+        };
+        // udev takes ~80ms to export the pins
+        for pin in connections.pins() {
+            if let Err(e) = pin.export() {
+                error!("Could not export {:?}: {}", pin, e);
+            }
         }
+        sleep(Duration::from_millis(100)).await;
+        connections
+    }
+    /// Every GPIO used, H-bridge enable pins included when driven with software PWM
+    fn pins(&self) -> impl Iterator<Item = &GpioPin> {
+        self.limit_switches
+            .values()
+            .chain(self.h_bridge.values().flat_map(|h_bridge| {
+                h_bridge
+                    .pins
+                    .iter()
+                    .chain(h_bridge.enable.as_ref().and_then(PwmOutput::pin))
+            }))
+            .chain(self.status_leds.values())
+    }
+    fn setup_pca9685(path: &str, pwm_freq: u16) -> Result<Pca9685<I2cdev>> {
+        const OSCILLATOR_FREQ: f32 = 25_000_000.0;
+        let prescale = (OSCILLATOR_FREQ / (4096.0 * pwm_freq as f32)).round() - 1.0;
+        let prescale = prescale.clamp(3.0, 255.0) as u8;
+        let dev = I2cdev::new(path)?;
+        let mut pwm_device = Pca9685::new(dev, pca9685::Address::default())
+            .map_err(|e| eyre!("Error creating PCA9685: {:?}", e))?;
+        debug!("Setting PCA9685 prescale to {} for {}Hz", prescale, pwm_freq);
+        pwm_device
+            .set_prescale(prescale)
+            .and_then(|_| pwm_device.enable())
+            .and_then(|_| pwm_device.set_all_on_off(&[0; 16], &[0; 16]))
+            .map_err(|e| eyre!("Error configuring PCA9685: {:?}", e))?;
+        Ok(pwm_device)
     }
     fn microseconds_to_analog_value(&self, microseconds: u16) -> u16 {
-        let microseconds = microseconds as f32;
-        let microseconds = microseconds / 1000_000.0;
-        let microseconds = microseconds * self.pwm_freq as f32;
-        let microseconds = microseconds * self.pwm_adc_max_value as f32;
-        microseconds as u16
+        microseconds_to_ticks(microseconds, self.pwm_freq as u32, self.pwm_adc_max_value)
     }
 
     pub fn setup_pins(&mut self) -> Result<()> {
@@ -115,10 +140,14 @@ impl LocalConnections {
             debug!("Setting up pin {:?}", input_pin);
             input_pin.set_direction(Direction::In)?;
         }
-        for output_pin in self.h_bridge.values_mut() {
-            debug!("Setting up motor pins {:?}", output_pin);
-            output_pin[0].set_direction(Direction::Out)?;
-            output_pin[1].set_direction(Direction::Out)?;
+        for h_bridge in self.h_bridge.values_mut() {
+            debug!("Setting up motor pins {:?}", h_bridge.pins);
+            h_bridge.pins[0].set_direction(Direction::Out)?;
+            h_bridge.pins[1].set_direction(Direction::Out)?;
+            if let Some(enable_pin) = h_bridge.enable.as_ref().and_then(PwmOutput::pin) {
+                debug!("Setting up software PWM pin {:?}", enable_pin);
+                enable_pin.set_direction(Direction::Out)?;
+            }
         }
         for output_pin in self.status_leds.values_mut() {
             debug!("Setting up led pins {:?}", output_pin);
@@ -127,77 +156,119 @@ impl LocalConnections {
         Ok(())
     }
 
-    pub fn respond(
-        &mut self,
-        lrq: LocalRequest,
-    ) -> Result<(oneshot::Sender<LocalResponse>, LocalResponse)> {
-        match lrq.body {
-            HardwareRequest::SwitchRead { switch } => {
-                let pin = self
-                    .limit_switches
-                    .get(&switch)
-                    .ok_or(Error::msg("Invalid switch id"))?;
-                let value = pin.get_value()?;
-                Ok((lrq.tx, LocalResponse::SwitchOn(value == 1)))
-            }
-            HardwareRequest::ServoWrite { servo, position, duty, start } => {
-                let value = duty.unwrap_or(self.microseconds_to_analog_value(position));
-                let start = start.unwrap_or(0);
-                debug!("Handling servo write to position: {}", position);
-                // self.pwm_device.set_channel_on_off(*self.servos
-                //     .get(&servo)
-                //     .ok_or(Error::msg("Invalid servo id"))?, 0, value).unwrap();
-                Ok((lrq.tx, LocalResponse::Ok))
-            }
-            HardwareRequest::LedWrite { led, state } => {
-                let pin = self
-                    .status_leds
-                    .get(&led)
-                    .ok_or(Error::msg("Invalid led id"))?;
-                pin.set_value(state)?;
-                Ok((lrq.tx, LocalResponse::Ok))
-            }
-            HardwareRequest::MotorWrite { motor, command } => {
-                let h_bridge = self
-                    .h_bridge
-                    .get(&motor)
-                    .ok_or(Error::msg("Invalid h-bridge id"))?;
-                let value = command[0];
-                self.write_h_bridge(*h_bridge, value)?;
-                Ok((lrq.tx, LocalResponse::Ok))
-            }
-            _ => Err(Error::msg("Could not handle request locally")),
-        }
+    /// Hands the status LEDs over to a background task showing `status`, or client set patterns.
+    /// Must be called after `setup_pins`.
+    pub fn start_status_indicator(&mut self, status: watch::Receiver<SystemStatus>) {
+        self.status_indicator = Some(status::spawn_indicator(self.status_leds.clone(), status));
+    }
+    fn send_status_command(&self, command: StatusCommand) -> Result<()> {
+        self.status_indicator
+            .as_ref()
+            .ok_or(Error::msg("Status indicator is not running"))?
+            .try_send(command)
+            .map_err(|e| eyre!("Error sending command to status indicator: {}", e))
     }
 
-    fn write_h_bridge(&mut self, h_bridge: HBridgePinPair, command: u8) -> Result<()> {
-        match command {
-            65..=127 | 193..=u8::MAX => {
-                h_bridge[0].set_value(1)?;
-                h_bridge[1].set_value(0)?;
-            }
-            1..=63 | 128..=190 => {
-                h_bridge[0].set_value(0)?;
-                h_bridge[1].set_value(1)?;
-            }
-            // The reason why 191 is here, is due rounding down in affine_transform in wroom
-            // With that, someone may think, that 191 corresponds to a rest position, which may be true
-            // for Sabertooth, but for the H-Bridge, there's no speed control, only discrete
-            // on/off.
-            0 | 64 | 191 | 192 => {
-                h_bridge[0].set_value(0)?;
-                h_bridge[1].set_value(0)?;
-            }
+    fn write_h_bridge(&mut self, motor: &str, speed: f32) -> Result<()> {
+        let h_bridge = self
+            .h_bridge
+            .get(motor)
+            .ok_or(Error::msg("Invalid h-bridge id"))?;
+        let pins = &h_bridge.pins;
+        // H-bridges without an enable pin have no speed control, only discrete on/off.
+        if speed > h_bridge.threshold {
+            pins[0].set_value(1)?;
+            pins[1].set_value(0)?;
+        } else if speed < -h_bridge.threshold {
+            pins[0].set_value(0)?;
+            pins[1].set_value(1)?;
+        } else {
+            pins[0].set_value(0)?;
+            pins[1].set_value(0)?;
+        }
+        if let Some(enable) = &h_bridge.enable {
+            let duty = if speed.abs() > h_bridge.threshold { speed.abs() } else { 0.0 };
+            enable.set_duty(duty, self.pwm_device.as_mut())?;
         }
         Ok(())
     }
 }
 
-impl LocalRequest {
-    pub fn from_hardware_request(
-        body: HardwareRequest,
-    ) -> (tokio::sync::oneshot::Receiver<LocalResponse>, Self) {
-        let (tx, rx) = tokio::sync::oneshot::channel();
-        (rx, Self { body, tx })
+impl Backend for LocalConnections {
+    fn devices(&self) -> Vec<(DeviceKind, String)> {
+        let switches = self.limit_switches.keys().map(|name| (DeviceKind::Switch, name.clone()));
+        let motors = self.h_bridge.keys().map(|name| (DeviceKind::Motor, name.clone()));
+        let leds = self.status_leds.keys().map(|name| (DeviceKind::Led, name.clone()));
+        let servos = self.servos.keys().map(|name| (DeviceKind::Servo, name.clone()));
+        // A pin can be shared, e.g. by a limit switch and an H-bridge
+        let numbers: BTreeSet<u64> = self.pins().map(GpioPin::number).collect();
+        let pins = numbers.into_iter().map(|number| (DeviceKind::Gpio, number.to_string()));
+        switches.chain(motors).chain(leds).chain(servos).chain(pins).collect()
+    }
+    async fn read_gpio(&mut self, number: u64) -> Result<u8> {
+        self.pins()
+            .find(|pin| pin.number() == number)
+            .ok_or_else(|| eyre!("GPIO {} is not used", number))?
+            .read_output()
+    }
+    async fn read_switch(&mut self, switch: &str) -> Result<bool> {
+        let pin = self
+            .limit_switches
+            .get(switch)
+            .ok_or(Error::msg("Invalid switch id"))?;
+        Ok(pin.get_value()? == 1)
+    }
+    async fn write_servo(&mut self, servo: &str, position: u16, duty: Option<u16>, start: Option<u16>) -> Result<()> {
+        let value = duty.unwrap_or(self.microseconds_to_analog_value(position));
+        let start = start.unwrap_or(0);
+        debug!("Handling servo write to position: {}", position);
+        let channel = *self
+            .servos
+            .get(servo)
+            .ok_or(Error::msg("Invalid servo id"))?;
+        self.pwm_device
+            .as_mut()
+            .ok_or(Error::msg("PCA9685 is not available"))?
+            .set_channel_on_off(channel, start, value)
+            .map_err(|e| eyre!("Error writing to PCA9685: {:?}", e))
+    }
+    async fn write_led(&mut self, led: &str, pattern: LedPattern) -> Result<()> {
+        if !self.status_leds.contains_key(led) {
+            return Err(Error::msg("Invalid led id"));
+        }
+        if !pattern.is_valid() {
+            return Err(eyre!("Invalid LED pattern {:?}", pattern));
+        }
+        self.send_status_command(StatusCommand::Led {
+            led: led.to_string(),
+            pattern,
+        })
+    }
+    async fn indicate_status(&mut self, colour: Colour, pattern: LedPattern) -> Result<()> {
+        if !pattern.is_valid() {
+            return Err(eyre!("Invalid LED pattern {:?}", pattern));
+        }
+        self.send_status_command(StatusCommand::Indicate { colour, pattern })
+    }
+    async fn release_status(&mut self) -> Result<()> {
+        self.send_status_command(StatusCommand::Release)
+    }
+    async fn set_input(&mut self, switch: &str, on: bool) -> Result<()> {
+        self.limit_switches
+            .get(switch)
+            .ok_or(Error::msg("Invalid switch id"))?
+            .set_input(on as u8)
+    }
+    async fn write_motor(&mut self, motor: &str, command: &[u8]) -> Result<()> {
+        match command {
+            [command] => self.write_h_bridge(motor, command_to_speed(*command)),
+            _ => Err(eyre!(
+                "MotorWrite Command received has invalid command length. Expected 1, got {}",
+                command.len()
+            )),
+        }
+    }
+    async fn set_motor(&mut self, motor: &str, speed: f32) -> Result<()> {
+        self.write_h_bridge(motor, speed)
     }
 }
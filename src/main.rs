@@ -1,12 +1,28 @@
 // Setup a tokio server which listens to UNIX socket connections
+mod backend;
+mod battery;
 mod config;
+mod control;
+mod drive;
+mod encoder;
+mod gpio;
+mod i2c;
+mod imu;
 mod local;
+mod motor;
 mod pad;
+mod pwm;
+mod sensor;
 mod server;
-use eyre::{Result, WrapErr};
+mod servo;
+mod sim;
+mod status;
+mod telemetry;
+use eyre::Result;
+use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::net::UnixListener;
-use tracing::{debug, error, info, warn};
+use tracing::{error, info};
 
 use git_version::git_version;
 const GIT_VERSION: &str = git_version!();
@@ -19,32 +35,91 @@ async fn main() -> Result<()> {
     tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
     info!("Starting spine version {}", GIT_VERSION);
     let config = Arc::new(config::load_config());
-    // Check if file /tmp/hardware.sock exists, if so, delete it
-    if std::path::Path::new("/tmp/hardware.sock").exists() {
-        std::fs::remove_file("/tmp/hardware.sock")?
+    // Listen on --socket if given, e.g. by tests running their own spine
+    let socket = std::env::args()
+        .skip_while(|arg| arg != "--socket")
+        .nth(1)
+        .unwrap_or_else(|| spine_client::DEFAULT_SOCKET.to_string());
+    // Check if the socket file exists, if so, delete it
+    if std::path::Path::new(&socket).exists() {
+        std::fs::remove_file(&socket)?
     }
-    let listener = UnixListener::bind("/tmp/hardware.sock").unwrap();
-    let (send_to_pad, mut recv_from_server) = tokio::sync::mpsc::channel::<pad::PadRequest>(100);
-    let (send_to_local, mut recv_from_server_local) =
-        tokio::sync::mpsc::channel::<local::LocalRequest>(100);
+    let listener = UnixListener::bind(&socket).unwrap();
+    let (status, _) = tokio::sync::watch::channel(status::SystemStatus::default());
+    let status = Arc::new(status);
 
-    let mut local_connections = local::LocalConnections::from_config(&config).await;
+    let simulate = std::env::args().any(|arg| arg == "--simulate");
+    let gpio = if simulate { gpio::GpioBackend::Mock } else { config.system.gpio };
+    let mut local_connections = local::LocalConnections::from_config(&config, gpio::Gpio::new(gpio)).await;
     local_connections.setup_pins()?;
-    let local_connections_handle = tokio::spawn(async move {
-        loop {
-            let request = recv_from_server_local.recv().await.unwrap();
-            let (send_to_server, response) = local_connections
-                .respond(request)
-                .wrap_err("Error responging to a LocalRequest")
-                .unwrap();
-            if matches!(response, local::LocalResponse::SwitchOn(_))
-                && send_to_server.send(response).is_err()
-            {
-                error!("Could not send back Switch state, receiver dropped.");
-                break;
-            }
-        }
-    });
+    local_connections.start_status_indicator(status.subscribe());
+    let pad_status = status.clone();
+
+    let mut backends = backend::Backends::default();
+    let pad = if simulate || config.pad.backend == config::PadBackend::Sim {
+        backends.spawn("PAD", sim::SimPad::from_config(&config))
+    } else {
+        backends.spawn("PAD", pad::PadState::from_config(&config))
+    };
+    backends.spawn("local", local_connections);
+    let mut pad_connected = pad.connected();
+    let backends = Arc::new(backends);
+
+    let (send_to_ramp, recv_from_server_ramp) = tokio::sync::mpsc::channel::<motor::MotorTarget>(100);
+    tokio::spawn(motor::ramp_motors(
+        config.clone(),
+        recv_from_server_ramp,
+        backends.clone(),
+        status.subscribe(),
+    ));
+    let (send_to_sweep, recv_from_server_sweep) = tokio::sync::mpsc::channel::<servo::ServoCommand>(100);
+    tokio::spawn(servo::sweep_servos(
+        config.clone(),
+        recv_from_server_sweep,
+        backends.clone(),
+    ));
+    let (joint_targets, joint_receivers): (HashMap<_, _>, Vec<_>) = config
+        .joints
+        .keys()
+        .map(|joint| {
+            let (target, receiver) = tokio::sync::watch::channel(None);
+            ((joint.clone(), target), (joint.clone(), receiver))
+        })
+        .unzip();
+    let (wheel_targets, wheel_receivers): (HashMap<_, _>, Vec<_>) = config
+        .wheels
+        .keys()
+        .map(|wheel| {
+            let (target, receiver) = tokio::sync::watch::channel(None);
+            ((wheel.clone(), target), (wheel.clone(), receiver))
+        })
+        .unzip();
+    let channels = server::Channels {
+        backends,
+        send_to_ramp,
+        send_to_sweep,
+        status,
+        joints: Arc::new(joint_targets),
+        wheels: Arc::new(wheel_targets),
+        reset_odometry: Arc::new(tokio::sync::Notify::new()),
+        telemetry: Arc::new(telemetry::Telemetry::default()),
+    };
+    for (joint, target) in joint_receivers {
+        tokio::spawn(control::control_joint(config.clone(), joint, target, channels.clone()));
+    }
+    for (wheel, target) in wheel_receivers {
+        tokio::spawn(control::control_wheel(config.clone(), wheel, target, channels.clone()));
+    }
+    tokio::spawn(drive::integrate_odometry(
+        config.clone(),
+        channels.clone(),
+        channels.reset_odometry.clone(),
+    ));
+    tokio::spawn(battery::monitor_battery(config.clone(), channels.clone()));
+    tokio::spawn(imu::run_imu(config.clone(), channels.clone()));
+    for device in config.system.i2c.keys() {
+        tokio::spawn(i2c::poll_device(config.clone(), device.clone(), channels.clone()));
+    }
     let server_handle = tokio::spawn(async move {
         loop {
             match listener.accept().await {
@@ -52,11 +127,10 @@ async fn main() -> Result<()> {
                     error!("Error accepting connection: {}", e);
                 }
                 Ok(accept_result) => {
-                    let pad_channel = send_to_pad.clone();
-                    let local_channel = send_to_local.clone();
+                    let channels = channels.clone();
                     let config = config.clone();
                     tokio::spawn(async move {
-                        server::handle_stream(&config, accept_result, pad_channel, local_channel)
+                        server::handle_stream(&config, accept_result, channels)
                             .await
                             .map_err(|e| error!("Error handling stream: {}", e))
                             .ok();
@@ -66,30 +140,10 @@ async fn main() -> Result<()> {
         }
     });
 
-    let mut interval = tokio::time::interval(std::time::Duration::from_millis(800));
-    let mut pad = pad::PadState::new();
-    pad.connect_device().await;
-
-    loop {
-        tokio::select! {
-            _ = interval.tick() => {
-                if pad.keep_alive().await.map_err(|e| error!("Error sending KeepAlive: {}", e)).is_err() {
-                    warn!("Lost connection to PAD, trying to reconnect...");
-                    pad.connect_device().await;
-                }
-            }
-            pad_req = recv_from_server.recv() => {
-                debug!("Got request from server: {:?}", pad_req);
-                let (send_to_server, response) = pad.respond(pad_req.unwrap()).await.wrap_err("Error responding to pad request").unwrap();
-                if (matches!(response, pad::PadResponse::EncoderValue(_)) || matches!(response, pad::PadResponse::SensorValue(_))) &&
-                    send_to_server.send(response).is_err() {
-                        error!("Could not send back encoder values, receiver dropped.");
-                        break;
-                }
-            }
-        }
+    while pad_connected.changed().await.is_ok() {
+        let connected = *pad_connected.borrow_and_update();
+        pad_status.send_modify(|status| status.pad_connected = connected);
     }
-    local_connections_handle.await.unwrap();
     server_handle.await.unwrap();
     Ok(())
 }
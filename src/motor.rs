@@ -0,0 +1,395 @@
+use crate::backend::Backends;
+use crate::config::Config;
+use crate::server::HardwareRequest;
+use crate::status::SystemStatus;
+use eyre::{eyre, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{mpsc, watch};
+use tokio::time::Duration;
+use tracing::{debug, trace};
+
+const RAMP_TICK: Duration = Duration::from_millis(20);
+
+/// Sabertooth simplified serial: channel 1 uses 1..=127 with 64 as stop,
+/// channel 2 uses 128..=255 with 192 as stop, and 0 stops both channels.
+pub fn sabertooth_command(channel: u8, speed: f32) -> u8 {
+    let speed = speed.clamp(-1.0, 1.0);
+    match channel {
+        2 if speed < 0.0 => (192.0 + speed * 64.0).round() as u8,
+        2 => (192.0 + speed * 63.0).round() as u8,
+        _ => (64.0 + speed * 63.0).round().clamp(1.0, 127.0) as u8,
+    }
+}
+
+/// Inverse of `sabertooth_command`, a command byte to a speed in -1.0..=1.0
+pub fn command_to_speed(command: u8) -> f32 {
+    match command {
+        1..=63 => -((64 - command) as f32) / 63.0,
+        65..=127 => (command - 64) as f32 / 63.0,
+        128..=190 => -((192 - command) as f32) / 64.0,
+        193..=u8::MAX => (command - 192) as f32 / 63.0,
+        // The reason why 191 is here, is due rounding down in affine_transform in wroom
+        // With that, someone may think, that 191 corresponds to a rest position.
+        0 | 64 | 191 | 192 => 0.0,
+    }
+}
+
+/// Smartelex serial frame: start byte, channel, direction (0 forward, 1 reverse), duty out of 255
+/// and a checksum, the wrapping sum of the channel, direction and duty.
+const SMARTELEX_START: u8 = 0xAA;
+pub fn smartelex_command(channel: u8, speed: f32) -> [u8; 5] {
+    let speed = speed.clamp(-1.0, 1.0);
+    let direction = (speed < 0.0) as u8;
+    let duty = (speed.abs() * 255.0).round() as u8;
+    let checksum = channel.wrapping_add(direction).wrapping_add(duty);
+    [SMARTELEX_START, channel, direction, duty, checksum]
+}
+
+/// Driver of a motor on the PAD, which decides the PAD operation and the commands it takes
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MotorDriver {
+    #[default]
+    Sabertooth,
+    /// Takes 5 byte frames, see `smartelex_command`
+    Smartelex,
+}
+impl MotorDriver {
+    /// The command that drives `channel` of the driver at `speed`
+    pub fn command(self, channel: u8, speed: f32) -> Vec<u8> {
+        match self {
+            Self::Sabertooth => vec![sabertooth_command(channel, speed)],
+            Self::Smartelex => smartelex_command(channel, speed).to_vec(),
+        }
+    }
+    /// The command that stops the motor on `channel`. A Sabertooth's 0 stops both of its channels.
+    pub fn stop(self, channel: u8) -> Vec<u8> {
+        match self {
+            Self::Sabertooth => vec![0],
+            Self::Smartelex => smartelex_command(channel, 0.0).to_vec(),
+        }
+    }
+}
+
+/// A `MotorWrite` command checked against the driver it is sent to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MotorCommand {
+    Sabertooth(u8),
+    Smartelex([u8; 5]),
+}
+impl MotorCommand {
+    pub fn parse(driver: MotorDriver, command: &[u8]) -> Result<Self> {
+        match (driver, command) {
+            (MotorDriver::Sabertooth, &[command]) => Ok(Self::Sabertooth(command)),
+            (MotorDriver::Smartelex, &[SMARTELEX_START, channel, direction, duty, checksum])
+                if direction <= 1 && checksum == channel.wrapping_add(direction).wrapping_add(duty) =>
+            {
+                Ok(Self::Smartelex([SMARTELEX_START, channel, direction, duty, checksum]))
+            }
+            (MotorDriver::Sabertooth, _) => Err(eyre!(
+                "Sabertooth commands are a single byte, got {:?}",
+                command
+            )),
+            (MotorDriver::Smartelex, _) => Err(eyre!(
+                "Expected a 5 byte Smartelex frame with a valid checksum, got {:?}",
+                command
+            )),
+        }
+    }
+    /// The command's speed in -1.0..=1.0, and the driver channel it's for if the command names one
+    pub fn speed(&self) -> (f32, Option<u8>) {
+        match *self {
+            Self::Sabertooth(0) => (0.0, None),
+            Self::Sabertooth(command) => (command_to_speed(command), Some(if command < 128 { 1 } else { 2 })),
+            Self::Smartelex([_, channel, direction, duty, _]) => {
+                let speed = duty as f32 / 255.0;
+                (if direction == 1 { -speed } else { speed }, Some(channel))
+            }
+        }
+    }
+}
+
+/// Scales the speed of a motor request by `factor`, leaving every other request untouched.
+/// A `MotorWrite` keeps the driver and channel of its command, and `[0]` still stops both
+/// channels of a Sabertooth.
+pub fn scale_motor_request(config: &Config, req: HardwareRequest, factor: f32) -> HardwareRequest {
+    match req {
+        HardwareRequest::MotorSet { motor, speed } => HardwareRequest::MotorSet {
+            motor,
+            speed: speed * factor,
+        },
+        HardwareRequest::MotorWrite { motor, command } => {
+            let driver = config.motor_driver(&motor);
+            match MotorCommand::parse(driver, &command) {
+                Ok(MotorCommand::Sabertooth(0)) => HardwareRequest::MotorWrite { motor, command },
+                Ok(parsed) => {
+                    let (speed, channel) = parsed.speed();
+                    HardwareRequest::MotorWrite {
+                        motor,
+                        command: driver.command(channel.unwrap_or_default(), speed * factor),
+                    }
+                }
+                // Stops of other drivers, and commands left for the backend to refuse
+                Err(_) => HardwareRequest::MotorWrite { motor, command },
+            }
+        }
+        req => req,
+    }
+}
+
+/// Wiring and mechanical quirks of a motor, applied to every speed before it reaches the driver
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(default)]
+pub struct MotorShaping {
+    pub invert: bool,
+    pub scale: f32,
+    /// Speeds with a smaller magnitude than this are treated as stop
+    pub deadband: f32,
+    /// Largest magnitude ever sent to the driver
+    pub max: f32,
+}
+impl Default for MotorShaping {
+    fn default() -> Self {
+        Self {
+            invert: false,
+            scale: 1.0,
+            deadband: 0.0,
+            max: 1.0,
+        }
+    }
+}
+impl MotorShaping {
+    pub fn apply(&self, speed: f32) -> f32 {
+        let speed = speed * self.scale;
+        let speed = if self.invert { -speed } else { speed };
+        if speed.abs() < self.deadband {
+            0.0
+        } else {
+            speed.clamp(-self.max, self.max)
+        }
+    }
+}
+
+/// Speed a ramped motor should reach, before shaping
+#[derive(Debug)]
+pub struct MotorTarget {
+    pub motor: String,
+    pub speed: f32,
+}
+
+/// Moves every ramped motor towards its target on a fixed tick, writing the intermediate speeds.
+/// Motors are assumed to be stopped when spine starts, and are stopped whenever they are inhibited.
+pub async fn ramp_motors(
+    config: Arc<Config>,
+    mut targets: mpsc::Receiver<MotorTarget>,
+    backends: Arc<Backends>,
+    status: watch::Receiver<SystemStatus>,
+) {
+    // Current and target speed of each motor
+    let mut ramps: HashMap<String, (f32, f32)> = HashMap::new();
+    let mut interval = tokio::time::interval(RAMP_TICK);
+    loop {
+        tokio::select! {
+            target = targets.recv() => match target {
+                Some(MotorTarget { motor, speed }) => {
+                    debug!("Ramping {} to {}", motor, speed);
+                    ramps.entry(motor).or_insert((0.0, 0.0)).1 = speed;
+                }
+                None => break,
+            },
+            _ = interval.tick() => {
+                if status.borrow().motors_inhibited() {
+                    ramps.values_mut().for_each(|ramp| *ramp = (0.0, 0.0));
+                    continue;
+                }
+                for (motor, (current, target)) in ramps.iter_mut() {
+                    if current == target {
+                        continue;
+                    }
+                    let max_step = config.max_acceleration(motor).unwrap_or(f32::INFINITY)
+                        * RAMP_TICK.as_secs_f32();
+                    *current += (*target - *current).clamp(-max_step, max_step);
+                    trace!("Ramped {} to {}", motor, current);
+                    let req = HardwareRequest::MotorSet {
+                        motor: motor.clone(),
+                        speed: *current,
+                    };
+                    backends.dispatch(config.lower_motor_request(req)).await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        toml::from_str(
+            r#"
+            [pad.motors]
+            drive = 0
+            arm = { port = 3, driver = "smartelex", max_acceleration = 2.0 }
+            [pad.encoders]
+            [pad.servos]
+            [system]
+            pca9685_path = "/dev/i2c-1"
+            [system.motors]
+            [system.limit_switches]
+            [system.status_leds]
+            [system.servos]
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn write(motor: &str, command: Vec<u8>) -> HardwareRequest {
+        HardwareRequest::MotorWrite {
+            motor: motor.to_string(),
+            command,
+        }
+    }
+
+    #[test]
+    fn sabertooth_stops_are_64_and_192() {
+        assert_eq!(sabertooth_command(1, 0.0), 64);
+        assert_eq!(sabertooth_command(2, 0.0), 192);
+        assert_eq!(command_to_speed(64), 0.0);
+        assert_eq!(command_to_speed(192), 0.0);
+    }
+
+    #[test]
+    fn sabertooth_full_speed_and_clamping() {
+        assert_eq!(sabertooth_command(1, 1.0), 127);
+        assert_eq!(sabertooth_command(1, -1.0), 1);
+        assert_eq!(sabertooth_command(2, 1.0), 255);
+        assert_eq!(sabertooth_command(2, -1.0), 128);
+        assert_eq!(sabertooth_command(1, 5.0), 127);
+        assert_eq!(sabertooth_command(2, -5.0), 128);
+    }
+
+    #[test]
+    fn sabertooth_zero_stops_both_channels() {
+        assert_eq!(command_to_speed(0), 0.0);
+        // Channel 1 never sends the shared stop byte, however slow the reverse
+        assert_eq!(sabertooth_command(1, -1.0), 1);
+    }
+
+    #[test]
+    fn sabertooth_commands_round_trip() {
+        for command in (1..=u8::MAX).filter(|&command| command != 191) {
+            let channel = if command < 128 { 1 } else { 2 };
+            assert_eq!(
+                sabertooth_command(channel, command_to_speed(command)),
+                command,
+                "command {}",
+                command
+            );
+        }
+        // Taken as a stop, see command_to_speed
+        assert_eq!(command_to_speed(191), 0.0);
+    }
+
+    #[test]
+    fn shaping_defaults_leave_speeds_alone() {
+        let shaping = MotorShaping::default();
+        for speed in [-1.0, -0.3, 0.0, 0.5, 1.0] {
+            assert_eq!(shaping.apply(speed), speed);
+        }
+    }
+
+    #[test]
+    fn shaping_scales_then_inverts_then_clamps() {
+        let shaping = MotorShaping {
+            invert: true,
+            scale: 2.0,
+            deadband: 0.0,
+            max: 0.8,
+        };
+        assert_eq!(shaping.apply(0.25), -0.5);
+        assert_eq!(shaping.apply(0.5), -0.8);
+        assert_eq!(shaping.apply(-1.0), 0.8);
+    }
+
+    #[test]
+    fn shaping_deadband_applies_after_scaling() {
+        let shaping = MotorShaping {
+            scale: 0.5,
+            deadband: 0.1,
+            ..Default::default()
+        };
+        assert_eq!(shaping.apply(0.19), 0.0);
+        assert_eq!(shaping.apply(-0.19), 0.0);
+        assert_eq!(shaping.apply(0.2), 0.1);
+    }
+
+    #[test]
+    fn commands_are_parsed_by_driver() {
+        assert_eq!(
+            MotorCommand::parse(MotorDriver::Sabertooth, &[100]).unwrap(),
+            MotorCommand::Sabertooth(100)
+        );
+        assert!(MotorCommand::parse(MotorDriver::Sabertooth, &[0xAA, 1, 0, 128, 129]).is_err());
+        assert_eq!(
+            MotorCommand::parse(MotorDriver::Smartelex, &[0xAA, 1, 0, 128, 129]).unwrap(),
+            MotorCommand::Smartelex([0xAA, 1, 0, 128, 129])
+        );
+        assert!(MotorCommand::parse(MotorDriver::Smartelex, &[100]).is_err());
+        // Bad checksum
+        assert!(MotorCommand::parse(MotorDriver::Smartelex, &[0xAA, 1, 0, 128, 130]).is_err());
+    }
+
+    #[test]
+    fn smartelex_frames_carry_channel_and_speed() {
+        assert_eq!(smartelex_command(2, -1.0), [0xAA, 2, 1, 255, 2]);
+        assert_eq!(MotorDriver::Smartelex.stop(1), vec![0xAA, 1, 0, 0, 1]);
+        let frame = MotorCommand::parse(MotorDriver::Smartelex, &smartelex_command(2, -0.5)).unwrap();
+        let (speed, channel) = frame.speed();
+        assert!((speed + 0.5).abs() < 0.01, "speed {}", speed);
+        assert_eq!(channel, Some(2));
+    }
+
+    #[test]
+    fn scaling_follows_the_configured_driver() {
+        let config = config();
+        match scale_motor_request(&config, write("drive", vec![127]), 0.5) {
+            HardwareRequest::MotorWrite { command, .. } => assert_eq!(command, vec![96]),
+            req => panic!("Scaled into {:?}", req),
+        }
+        match scale_motor_request(&config, write("drive", vec![0]), 0.5) {
+            HardwareRequest::MotorWrite { command, .. } => assert_eq!(command, vec![0]),
+            req => panic!("Scaled into {:?}", req),
+        }
+        match scale_motor_request(&config, write("arm", smartelex_command(2, -1.0).to_vec()), 0.5) {
+            HardwareRequest::MotorWrite { command, .. } => assert_eq!(command, smartelex_command(2, -0.5).to_vec()),
+            req => panic!("Scaled into {:?}", req),
+        }
+    }
+
+    #[test]
+    fn smartelex_motors_are_lowered_to_frames() {
+        let config = config();
+        let set = HardwareRequest::MotorSet {
+            motor: "arm".to_string(),
+            speed: 0.5,
+        };
+        match config.lower_motor_request(set) {
+            HardwareRequest::MotorWrite { command, .. } => assert_eq!(command, smartelex_command(1, 0.5).to_vec()),
+            req => panic!("Lowered into {:?}", req),
+        }
+        // Written to the configured channel
+        match config.lower_motor_request(write("arm", smartelex_command(2, 1.0).to_vec())) {
+            HardwareRequest::MotorWrite { command, .. } => assert_eq!(command, smartelex_command(1, 1.0).to_vec()),
+            req => panic!("Lowered into {:?}", req),
+        }
+        match config.lower_motor_request(write("arm", vec![0])) {
+            HardwareRequest::MotorWrite { command, .. } => assert_eq!(command, MotorDriver::Smartelex.stop(1)),
+            req => panic!("Lowered into {:?}", req),
+        }
+        assert_eq!(config.command_speed("arm", &[0]), Some(0.0));
+        assert_eq!(config.max_acceleration("arm"), Some(2.0));
+    }
+}
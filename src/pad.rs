@@ -1,69 +1,118 @@
-use crate::server::HardwareRequest;
+use crate::backend::{Backend, DeviceKind};
+use crate::config::Config;
+use crate::encoder::EncoderTracker;
+use crate::motor::{MotorCommand, MotorDriver};
+use crate::servo::microseconds_to_ticks;
 use eyre::eyre;
 use eyre::Result;
-use postcard::{from_bytes, to_slice};
-use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use spine_protocol::{decode, encode, EncoderCounts, Operation, ENCODER_COUNT, FRAME_END, MAX_MESSAGE_LEN};
+use std::collections::HashMap;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::oneshot;
-use tokio_serial::{SerialPortType, SerialStream};
-use tracing::{debug, error, info, span, trace, warn, Level};
+use tokio::time::{timeout_at, Duration, Instant};
+use tokio_serial::{ClearBuffer, SerialPort, SerialPortType, SerialStream};
+use tracing::{debug, error, info, trace};
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
-enum Operation {
-    KeepAlive,
-    SabertoothWrite(u8, u8),
-    SensorRead,
-    EncoderRead,
-    PwmStartEndWrite(u8, u16, u16),
-    VersionReport,
-    EncoderReset,
-}
-
-#[derive(Debug)]
-pub struct PadRequest {
-    pub id: u8,
-    pub body: HardwareRequest,
-    tx: tokio::sync::oneshot::Sender<PadResponse>,
-}
-impl PadRequest {
-    pub fn from_hardware_request(
-        id: u8,
-        hwrq: HardwareRequest,
-    ) -> (tokio::sync::oneshot::Receiver<PadResponse>, Self) {
-        let (pad_tx, server_rx) = tokio::sync::oneshot::channel();
-        (
-            server_rx,
-            Self {
-                id,
-                body: hwrq,
-                tx: pad_tx,
-            },
-        )
-    }
-}
-#[derive(Debug, Clone)]
-pub enum PadResponse {
-    EncoderValue(i32),
-    SensorValue(u16),
-    Ok,
-}
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
 
 pub struct PadState {
     serial: Option<SerialStream>,
-    pwm_freq: u32,
+    /// Bytes read from the PAD that don't make up a whole frame yet, or follow the last reply
+    pending: Vec<u8>,
+    /// Serial device to use instead of searching for the PAD by its USB IDs
+    path: Option<String>,
+    pwm_freq: u16,
     pwm_adc_max_value: u16,
+    encoders: [EncoderTracker; ENCODER_COUNT],
+    /// Port or channel on the PAD of every device, by name
+    motors: HashMap<String, u8>,
+    motor_drivers: HashMap<String, MotorDriver>,
+    servos: HashMap<String, u8>,
+    encoder_ports: HashMap<String, u8>,
+    sensors: HashMap<String, u8>,
 }
 impl PadState {
-    pub fn new() -> Self {
+    pub fn from_config(config: &Config) -> Self {
+        let pad = &config.pad;
         Self {
             serial: None,
-            pwm_freq: 60,
+            pending: Vec::new(),
+            path: pad.path.clone(),
+            pwm_freq: pad.pwm_freq,
+            encoders: Default::default(),
             pwm_adc_max_value: 4095,
+            motors: pad.motors.iter().map(|(name, motor)| (name.clone(), motor.port())).collect(),
+            motor_drivers: pad.motors.iter().map(|(name, motor)| (name.clone(), motor.driver())).collect(),
+            servos: pad.servos.iter().map(|(name, servo)| (name.clone(), servo.channel())).collect(),
+            encoder_ports: pad.encoders.iter().map(|(name, encoder)| (name.clone(), encoder.port())).collect(),
+            sensors: pad.sensors.iter().map(|(name, sensor)| (name.clone(), sensor.channel())).collect(),
         }
     }
-    pub async fn connect_device(&mut self) {
+    fn lookup(devices: &HashMap<String, u8>, name: &str) -> Result<u8> {
+        devices
+            .get(name)
+            .copied()
+            .ok_or_else(|| eyre!("{} is not connected to the PAD", name))
+    }
+    fn encoder(&mut self, port: u8) -> Result<&mut EncoderTracker> {
+        self.encoders
+            .get_mut(port as usize)
+            .ok_or_else(|| eyre!("The PAD has no encoder port {}", port))
+    }
+    fn serial(&mut self) -> Result<&mut SerialStream> {
+        self.serial.as_mut().ok_or_else(|| eyre!("No PAD serial device found"))
+    }
+    async fn write_operation(&mut self, op: &Operation) -> Result<()> {
+        let mut buf = [0u8; MAX_MESSAGE_LEN];
+        let coded = encode(op, &mut buf)?;
+        // Drop anything left over from a reply that came too late, so it isn't taken for the next one
+        self.pending.clear();
+        let serial = self.serial()?;
+        serial.clear(ClearBuffer::Input)?;
+        serial.write_all(coded).await?;
+        trace!("Written bytes: {:?}", coded);
+        Ok(())
+    }
+    /// Reads until a whole frame has arrived and decodes it, keeping any bytes after it
+    async fn read_reply<T: DeserializeOwned>(&mut self) -> Result<T> {
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        loop {
+            if let Some(end) = self.pending.iter().position(|&byte| byte == FRAME_END) {
+                let mut frame: Vec<u8> = self.pending.drain(..=end).collect();
+                trace!("Read frame: {:?}", frame);
+                return decode(&mut frame).map_err(|e| eyre!("Could not decode reply from the PAD: {}", e));
+            }
+            if self.pending.len() > MAX_MESSAGE_LEN {
+                let dropped = self.pending.len();
+                self.pending.clear();
+                return Err(eyre!("Dropped {} bytes from the PAD without a frame end", dropped));
+            }
+            let mut buf = [0u8; MAX_MESSAGE_LEN];
+            let read = match timeout_at(deadline, self.serial()?.read(&mut buf)).await {
+                Ok(read) => read?,
+                Err(_) => {
+                    // The rest of this reply would be taken as the start of the next one
+                    self.pending.clear();
+                    return Err(eyre!("PAD did not reply within {:?}", REPLY_TIMEOUT));
+                }
+            };
+            if read == 0 {
+                return Err(eyre!("PAD serial device closed"));
+            }
+            self.pending.extend_from_slice(&buf[..read]);
+        }
+    }
+    async fn connect_device(&mut self) {
         const VID: u16 = 0x2E8A;
         const PID: u16 = 0x000A;
+        if let Some(path) = self.path.clone() {
+            info!("Connecting to PAD at {}", path);
+            if let Err(e) = self.setup_serial(&path).await {
+                error!("Error setting up serial port: {}", e);
+                self.serial = None;
+            }
+            return;
+        }
         if let Err(e) = serialport::available_ports() {
             error!("Error listing serial ports: {}", e);
             return;
@@ -78,159 +127,149 @@ impl PadState {
             if let SerialPortType::UsbPort(info) = &port.port_type {
                 if info.vid == VID && info.pid == PID {
                     info!("Found pad device!");
-                    self.setup_serial(&port)
-                        .await
-                        .map_err(|e| error!("Error setting up serial port: {}", e))
-                        .ok();
+                    if let Err(e) = self.setup_serial(&port.port_name).await {
+                        error!("Error setting up serial port: {}", e);
+                        self.serial = None;
+                    }
                 }
             }
         }
     }
-    async fn setup_serial(&mut self, port: &serialport::SerialPortInfo) -> Result<()> {
+    async fn setup_serial(&mut self, port_name: &str) -> Result<()> {
+        self.pending.clear();
         self.serial = Some(SerialStream::open(
-            &serialport::new(&port.port_name, 9600).timeout(std::time::Duration::from_millis(1000)),
+            &serialport::new(port_name, 9600).timeout(std::time::Duration::from_millis(1000)),
         )?);
         debug!("Trying to get version");
-        let mut buf = [0u8; 64];
-        let op = Operation::VersionReport;
-        let coded = to_slice(&op, &mut buf)?;
-        self.serial
-            .as_mut()
-            .ok_or_else(|| eyre!("No PAD serial device found"))?
-            .write_all(coded)
-            .await?;
-        let read = self
-            .serial
-            .as_mut()
-            .ok_or_else(|| eyre!("No PAD serial device found"))?
-            .read(&mut buf)
-            .await?;
-        let pad_version: String = from_bytes(&buf[..read])?;
+        self.write_operation(&Operation::VersionReport).await?;
+        let pad_version: String = self.read_reply().await?;
         info!("PAD reported version: {}", pad_version);
+        self.write_operation(&Operation::PwmFrequencyWrite(self.pwm_freq)).await?;
+        debug!("Set PAD PWM frequency to {}Hz", self.pwm_freq);
+        // The PAD may have been reset, carry on counting from its current readings
+        self.encoders.iter_mut().for_each(EncoderTracker::rebase);
         Ok(())
     }
-    pub async fn keep_alive(&mut self) -> Result<()> {
-        let _span_ = span!(Level::TRACE, "PadState::keep_alive").entered();
-        let mut buf = [0u8; 64];
-        let op = Operation::KeepAlive;
-        let coded = to_slice(&op, &mut buf)?;
-        self.serial
-            .as_mut()
-            .ok_or_else(|| eyre!("No PAD serial device found"))?
-            .write_all(coded)
-            .await?;
+    fn microseconds_to_analog_value(&self, microseconds: u16) -> u16 {
+        microseconds_to_ticks(microseconds, self.pwm_freq as u32, self.pwm_adc_max_value)
+    }
+    async fn read_encoders(&mut self) -> Result<()> {
+        self.write_operation(&Operation::EncoderRead).await?;
+        let encoder_values: EncoderCounts = self.read_reply().await?;
+        debug!("Encoder values: {:?}", encoder_values);
+        for (tracker, raw) in self.encoders.iter_mut().zip(encoder_values) {
+            tracker.update(raw);
+        }
+        Ok(())
+    }
+}
+impl Backend for PadState {
+    fn devices(&self) -> Vec<(DeviceKind, String)> {
+        let named = |kind, devices: &HashMap<String, u8>| {
+            devices.keys().map(move |name| (kind, name.clone())).collect::<Vec<_>>()
+        };
+        [
+            named(DeviceKind::Motor, &self.motors),
+            named(DeviceKind::Servo, &self.servos),
+            named(DeviceKind::Encoder, &self.encoder_ports),
+            named(DeviceKind::Sensor, &self.sensors),
+        ]
+        .concat()
+    }
+    async fn connect(&mut self) {
+        self.connect_device().await
+    }
+    async fn keep_alive(&mut self) -> Result<()> {
+        self.write_operation(&Operation::KeepAlive).await?;
         trace!("Sent keep alive");
-        trace!("Written bytes: {:?}", coded);
         Ok(())
     }
-    fn microseconds_to_analog_value(&self, microseconds: u16) -> u16 {
-        let microseconds = microseconds as f32;
-        let microseconds = microseconds / 1000_000.0;
-        let microseconds = microseconds * self.pwm_freq as f32;
-        let microseconds = microseconds * self.pwm_adc_max_value as f32;
-        microseconds as u16
-    }
-    pub async fn respond(
-        &mut self,
-        pad_rq: PadRequest,
-    ) -> Result<(oneshot::Sender<PadResponse>, PadResponse)> {
-        let _span_ = span!(Level::TRACE, "PadState::respond", pad_rq = ?pad_rq).entered();
-        match pad_rq.body {
-            HardwareRequest::ServoWrite {
-                servo: _,
-                position: value,
-                duty,
-                start,
-            } => {
-                let op = Operation::PwmStartEndWrite(pad_rq.id, start.unwrap_or(0), duty.unwrap_or(self.microseconds_to_analog_value(value)));
-                let mut buf = [0u8; 64];
-                let coded = to_slice(&op, &mut buf)?;
-                self.serial
-                    .as_mut()
-                    .ok_or_else(|| eyre!("No PAD serial device found"))?
-                    .write_all(coded)
-                    .await?;
-                debug!("Written servo: {:?}", coded);
-                Ok((pad_rq.tx, PadResponse::Ok))
-            }
-            HardwareRequest::MotorWrite { motor: _, command } => {
-                let op = match command.len() {
-                    1 => Operation::SabertoothWrite(pad_rq.id, command[0]),
-                    _ => panic!("MotorWrite Command received has invalid command length. Expected 1, got {}. Command: {:?}", command.len(), command),
-                };
-                let mut buf = [0u8; 64];
-                let coded = to_slice(&op, &mut buf)?;
-                self.serial
-                    .as_mut()
-                    .ok_or_else(|| eyre!("No PAD serial device found"))?
-                    .write_all(coded)
-                    .await?;
-                debug!("Written bytes: {:?}", coded);
-                Ok((pad_rq.tx, PadResponse::Ok))
-            }
-            HardwareRequest::EncoderRead { encoder: _ } => {
-                let op = Operation::EncoderRead;
-                let mut buf = [0u8; 64];
-                let coded = to_slice(&op, &mut buf)?;
-                self.serial
-                    .as_mut()
-                    .ok_or_else(|| eyre!("No PAD serial device found"))?
-                    .write_all(coded)
-                    .await?;
-                let read = self
-                    .serial
-                    .as_mut()
-                    .ok_or_else(|| eyre!("No PAD serial device found"))?
-                    .read(&mut buf)
-                    .await?;
-                let encoder_values: [i32; 6] = from_bytes(&buf[..read])?;
-                debug!("Encoder values: {:?}", encoder_values);
-                Ok((
-                    pad_rq.tx,
-                    PadResponse::EncoderValue(encoder_values[pad_rq.id as usize]),
-                ))
-            }
-            HardwareRequest::EncoderReset => {
-                let op = Operation::EncoderReset;
-                let mut buf = [0u8; 64];
-                let coded = to_slice(&op, &mut buf)?;
-                self.serial
-                    .as_mut()
-                    .ok_or_else(|| eyre!("No PAD serial device found"))?
-                    .write_all(coded)
-                    .await?;
-                debug!("Written bytes: {:?}", coded);
-                Ok((pad_rq.tx, PadResponse::Ok))
-            }
-            HardwareRequest::SensorRead => {
-                let op = Operation::SensorRead;
-                let mut buf = [0u8; 64];
-                let coded = to_slice(&op, &mut buf)?;
-                self.serial
-                    .as_mut()
-                    .ok_or_else(|| eyre!("No PAD serial device found"))?
-                    .write_all(coded)
-                    .await?;
-                let read = self
-                    .serial
-                    .as_mut()
-                    .ok_or_else(|| eyre!("No PAD serial device found"))?
-                    .read(&mut buf)
-                    .await?;
-                let sensor_values: u16 = from_bytes(&buf[..read])?;
-                debug!("Sensor values: {:?}", sensor_values);
-                Ok((
-                    pad_rq.tx,
-                    PadResponse::SensorValue(sensor_values),
-                ))
-            }
-            HardwareRequest::SwitchRead {switch: _} | HardwareRequest::LedWrite {led: _, state: _} => {
-                warn!(
-                    "PadState::respond: Unimplemented request: {:?}",
-                    pad_rq.body
-                );
-                Ok((pad_rq.tx, PadResponse::Ok))
-            }
+    async fn write_motor(&mut self, motor: &str, command: &[u8]) -> Result<()> {
+        let port = Self::lookup(&self.motors, motor)?;
+        let driver = self.motor_drivers.get(motor).copied().unwrap_or_default();
+        let op = match MotorCommand::parse(driver, command)? {
+            MotorCommand::Sabertooth(command) => Operation::SabertoothWrite(port, command),
+            MotorCommand::Smartelex(frame) => Operation::SmartelexWrite(port, frame),
+        };
+        self.write_operation(&op).await?;
+        debug!("Written motor {}: {:?}", motor, op);
+        Ok(())
+    }
+    async fn write_servo(&mut self, servo: &str, position: u16, duty: Option<u16>, start: Option<u16>) -> Result<()> {
+        let channel = Self::lookup(&self.servos, servo)?;
+        let duty = duty.unwrap_or(self.microseconds_to_analog_value(position));
+        let op = Operation::PwmStartEndWrite(channel, start.unwrap_or(0), duty);
+        self.write_operation(&op).await?;
+        debug!("Written servo {}: {:?}", servo, op);
+        Ok(())
+    }
+    async fn read_encoder(&mut self, encoder: &str) -> Result<i64> {
+        let port = Self::lookup(&self.encoder_ports, encoder)?;
+        self.read_encoders().await?;
+        Ok(self.encoder(port)?.value())
+    }
+    async fn zero_encoder(&mut self, encoder: &str) -> Result<()> {
+        // The PAD can only reset every encoder at once, zero this one on our side
+        let port = Self::lookup(&self.encoder_ports, encoder)?;
+        self.read_encoders().await?;
+        self.encoder(port)?.zero();
+        Ok(())
+    }
+    async fn reset_encoders(&mut self) -> Result<()> {
+        self.write_operation(&Operation::EncoderReset).await?;
+        self.encoders = Default::default();
+        Ok(())
+    }
+    async fn read_sensor(&mut self, sensor: &str) -> Result<u16> {
+        let channel = Self::lookup(&self.sensors, sensor)?;
+        self.write_operation(&Operation::AdcRead(channel)).await?;
+        let value: u16 = self.read_reply().await?;
+        debug!("Sensor {} value: {}", sensor, value);
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        toml::from_str(
+            r#"
+            [pad.motors]
+            [pad.encoders]
+            [pad.servos]
+            [system]
+            pca9685_path = "/dev/i2c-1"
+            [system.motors]
+            [system.limit_switches]
+            [system.status_leds]
+            [system.servos]
+            "#,
+        )
+        .unwrap()
+    }
+
+    async fn reply(pad_end: &mut SerialStream, values: &[u16]) {
+        for value in values {
+            let mut buf = [0u8; MAX_MESSAGE_LEN];
+            pad_end.write_all(encode(value, &mut buf).unwrap()).await.unwrap();
         }
     }
+
+    #[tokio::test]
+    async fn stale_replies_are_dropped_before_a_request() {
+        let (ours, mut pad_end) = SerialStream::pair().unwrap();
+        let mut pad = PadState::from_config(&config());
+        pad.serial = Some(ours);
+
+        // A late reply arrives along with the one asked for
+        pad.write_operation(&Operation::AdcRead(0)).await.unwrap();
+        reply(&mut pad_end, &[1, 2]).await;
+        assert_eq!(pad.read_reply::<u16>().await.unwrap(), 1);
+
+        pad.write_operation(&Operation::AdcRead(1)).await.unwrap();
+        reply(&mut pad_end, &[3]).await;
+        assert_eq!(pad.read_reply::<u16>().await.unwrap(), 3);
+    }
 }
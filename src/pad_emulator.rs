@@ -0,0 +1,303 @@
+// Fake PAD on a pseudo-terminal, for running spine's serial code without the board.
+// Point `pad.path` in spine's config at the printed path, or at the --link symlink which follows
+// reconnects. Faults are injected with commands on stdin or from a --script file, one per line:
+//   encoder <index> <counts>   set the raw count of an encoder
+//   adc <channel> <value>      set the reading of an ADC channel
+//   version <text>             set the reported firmware version
+//   drop <n>                   don't reply to the next n requests
+//   garbage <n>                reply to the next n requests with bytes that don't decode
+//   delay <ms>                 wait before every reply, 0 to stop
+//   disconnect                 close the pty and open a new one
+//   sleep <ms>                 wait before the next command, for scripts
+use serde::Serialize;
+use spine_protocol::{
+    encode, Accumulator, AdcReading, EncoderCounts, FeedResult, Operation, ENCODER_COUNT, FRAME_END,
+    MAX_MESSAGE_LEN,
+};
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::Duration;
+
+#[derive(Default)]
+struct Faults {
+    drop: u32,
+    garbage: u32,
+    delay: Duration,
+    disconnect: bool,
+}
+
+struct Pad {
+    version: String,
+    encoders: EncoderCounts,
+    adc: HashMap<u8, AdcReading>,
+    faults: Faults,
+}
+
+struct Pty {
+    master: File,
+    // Held open so reads from the master don't fail while spine isn't connected
+    _slave: File,
+    path: PathBuf,
+}
+impl Pty {
+    fn open() -> io::Result<Self> {
+        // SAFETY: plain libc calls on a descriptor owned here, ptsname_r writes into `name`
+        let (master, path) = unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let master = File::from_raw_fd(fd);
+            if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut name = [0 as libc::c_char; 128];
+            if libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let path = CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned();
+            (master, PathBuf::from(path))
+        };
+        let slave = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&path)?;
+        // SAFETY: termios is plain data, filled in by tcgetattr before use
+        unsafe {
+            let mut termios: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(slave.as_raw_fd(), &mut termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            libc::cfmakeraw(&mut termios);
+            if libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(Self {
+            master,
+            _slave: slave,
+            path,
+        })
+    }
+    /// Waits up to `timeout` for bytes from spine
+    fn readable(&self, timeout: Duration) -> io::Result<bool> {
+        let mut poll = libc::pollfd {
+            fd: self.master.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: a single valid pollfd
+        match unsafe { libc::poll(&mut poll, 1, timeout.as_millis() as libc::c_int) } {
+            n if n < 0 => Err(io::Error::last_os_error()),
+            n => Ok(n > 0),
+        }
+    }
+}
+
+fn open_pty(link: &Option<PathBuf>) -> io::Result<Pty> {
+    let pty = Pty::open()?;
+    println!("PAD emulator listening on {}", pty.path.display());
+    if let Some(link) = link {
+        std::fs::remove_file(link).ok();
+        std::os::unix::fs::symlink(&pty.path, link)?;
+        println!("Linked {} to it", link.display());
+    }
+    Ok(pty)
+}
+
+fn reply<T: Serialize>(pty: &mut Pty, pad: &Arc<Mutex<Pad>>, value: &T) -> io::Result<()> {
+    let (delay, garbage) = {
+        let mut pad = pad.lock().unwrap();
+        let faults = &mut pad.faults;
+        if faults.drop > 0 {
+            faults.drop -= 1;
+            println!("Dropped reply");
+            return Ok(());
+        }
+        let garbage = faults.garbage > 0;
+        faults.garbage = faults.garbage.saturating_sub(1);
+        (faults.delay, garbage)
+    };
+    sleep(delay);
+    if garbage {
+        println!("Replied with garbage");
+        // A frame that doesn't decode, spine should resync at its end
+        let mut garbage = [0xff; 9];
+        garbage[8] = FRAME_END;
+        return pty.master.write_all(&garbage);
+    }
+    let mut buf = [0u8; MAX_MESSAGE_LEN];
+    let coded = encode(value, &mut buf).map_err(io::Error::other)?;
+    pty.master.write_all(coded)
+}
+
+fn respond(pty: &mut Pty, pad: &Arc<Mutex<Pad>>, op: Operation) -> io::Result<()> {
+    println!("Received {:?}", op);
+    match op {
+        Operation::KeepAlive
+        | Operation::SabertoothWrite(_, _)
+        | Operation::SmartelexWrite(_, _)
+        | Operation::PwmStartEndWrite(_, _, _)
+        | Operation::PwmFrequencyWrite(_) => Ok(()),
+        Operation::EncoderReset => {
+            pad.lock().unwrap().encoders = [0; ENCODER_COUNT];
+            Ok(())
+        }
+        Operation::VersionReport => {
+            let version = pad.lock().unwrap().version.clone();
+            reply(pty, pad, &version)
+        }
+        Operation::EncoderRead => {
+            let encoders = pad.lock().unwrap().encoders;
+            reply(pty, pad, &encoders)
+        }
+        Operation::SensorRead | Operation::AdcRead(_) => {
+            let channel = match op {
+                Operation::AdcRead(channel) => channel,
+                _ => 0,
+            };
+            let value: AdcReading = pad.lock().unwrap().adc.get(&channel).copied().unwrap_or(0);
+            reply(pty, pad, &value)
+        }
+    }
+}
+
+/// Answers spine until told to disconnect
+fn serve(pty: &mut Pty, pad: &Arc<Mutex<Pad>>) -> io::Result<()> {
+    let mut accumulator = Accumulator::new();
+    let mut buf = [0u8; 256];
+    loop {
+        if std::mem::take(&mut pad.lock().unwrap().faults.disconnect) {
+            return Ok(());
+        }
+        if !pty.readable(Duration::from_millis(100))? {
+            continue;
+        }
+        let read = pty.master.read(&mut buf)?;
+        // Read the way the firmware does, a read can hold several frames or part of one
+        let mut window = &buf[..read];
+        while !window.is_empty() {
+            window = match accumulator.feed::<Operation>(window) {
+                FeedResult::Consumed => break,
+                FeedResult::OverFull(rest) => {
+                    println!("Dropped a frame longer than {} bytes", MAX_MESSAGE_LEN);
+                    rest
+                }
+                FeedResult::DeserError(rest) => {
+                    println!("Could not decode a frame");
+                    rest
+                }
+                FeedResult::Success { data, remaining } => {
+                    respond(pty, pad, data)?;
+                    remaining
+                }
+            };
+        }
+    }
+}
+
+fn command(pad: &Arc<Mutex<Pad>>, line: &str) -> Result<(), String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let number = |i: usize| -> Result<i64, String> {
+        words
+            .get(i)
+            .ok_or(format!("Missing argument {} of {:?}", i, line))?
+            .parse::<i64>()
+            .map_err(|e| format!("Bad argument {} of {:?}: {}", i, line, e))
+    };
+    let mut pad = pad.lock().unwrap();
+    match words.first().copied() {
+        None => {}
+        Some(comment) if comment.starts_with('#') => {}
+        Some("encoder") => {
+            let index = number(1)? as usize;
+            let encoder = pad
+                .encoders
+                .get_mut(index)
+                .ok_or(format!("No encoder {}", index))?;
+            *encoder = number(2)? as i32;
+        }
+        Some("adc") => {
+            let channel = number(1)? as u8;
+            pad.adc.insert(channel, number(2)? as u16);
+        }
+        Some("version") => pad.version = words[1..].join(" "),
+        Some("drop") => pad.faults.drop = number(1)? as u32,
+        Some("garbage") => pad.faults.garbage = number(1)? as u32,
+        Some("delay") => pad.faults.delay = Duration::from_millis(number(1)? as u64),
+        Some("disconnect") => pad.faults.disconnect = true,
+        Some("sleep") => {
+            let duration = Duration::from_millis(number(1)? as u64);
+            drop(pad);
+            sleep(duration);
+        }
+        Some(other) => return Err(format!("Unknown command {}", other)),
+    }
+    Ok(())
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let mut link = None;
+    let mut script = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--link" => link = args.next().map(PathBuf::from),
+            "--script" => script = args.next().map(PathBuf::from),
+            _ => {
+                eprintln!("Usage: pad_emulator [--link <path>] [--script <file>]");
+                std::process::exit(2);
+            }
+        }
+    }
+    let pad = Arc::new(Mutex::new(Pad {
+        version: format!("pad_emulator {}", env!("CARGO_PKG_VERSION")),
+        encoders: [0; ENCODER_COUNT],
+        adc: HashMap::new(),
+        faults: Faults::default(),
+    }));
+
+    let commands_pad = pad.clone();
+    std::thread::spawn(move || {
+        let script = script.map(|path| {
+            File::open(&path).unwrap_or_else(|e| panic!("Can't open {}: {}", path.display(), e))
+        });
+        let lines: Box<dyn Iterator<Item = io::Result<String>>> = match script {
+            Some(file) => Box::new(
+                BufReader::new(file)
+                    .lines()
+                    .chain(io::stdin().lock().lines()),
+            ),
+            None => Box::new(io::stdin().lock().lines()),
+        };
+        for line in lines.map_while(Result::ok) {
+            if let Err(e) = command(&commands_pad, &line) {
+                println!("{}", e);
+            }
+        }
+    });
+
+    loop {
+        let mut pty = match open_pty(&link) {
+            Ok(pty) => pty,
+            Err(e) => {
+                eprintln!("Could not open a pty: {}", e);
+                std::process::exit(1);
+            }
+        };
+        match serve(&mut pty, &pad) {
+            Ok(()) => println!("Disconnecting"),
+            Err(e) => println!("Error talking to spine: {}, reopening", e),
+        }
+        drop(pty);
+        sleep(Duration::from_millis(500));
+    }
+}
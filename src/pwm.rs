@@ -0,0 +1,150 @@
+use crate::config::PwmOutputConfig;
+use crate::gpio::{Gpio, GpioPin};
+use eyre::{eyre, Result};
+use linux_embedded_hal::I2cdev;
+use pwm_pca9685::{Channel, Pca9685};
+use std::path::PathBuf;
+use tokio::sync::watch;
+use tokio::time::{sleep, Duration};
+use tracing::{debug, error};
+
+/// An output whose duty cycle can be varied, used as the enable pin of an H-bridge.
+pub enum PwmOutput {
+    Sysfs(SysfsPwm),
+    Pca9685(Channel),
+    Software(SoftwarePwm),
+}
+
+impl PwmOutput {
+    pub fn from_config(config: &PwmOutputConfig, gpio: &Gpio) -> Result<Self> {
+        match config {
+            PwmOutputConfig::Sysfs {
+                chip,
+                channel,
+                frequency,
+            } => Ok(Self::Sysfs(SysfsPwm::new(*chip, *channel, *frequency)?)),
+            PwmOutputConfig::Pca9685 { channel } => Ok(Self::Pca9685(pca9685_channel(*channel)?)),
+            PwmOutputConfig::Software { pin, frequency } => {
+                Ok(Self::Software(SoftwarePwm::spawn(gpio.pin(*pin), *frequency)))
+            }
+        }
+    }
+    /// Sets the duty cycle, `duty` is clamped to 0.0..=1.0
+    pub fn set_duty(&self, duty: f32, pca9685: Option<&mut Pca9685<I2cdev>>) -> Result<()> {
+        let duty = duty.clamp(0.0, 1.0);
+        match self {
+            Self::Sysfs(pwm) => pwm.set_duty(duty),
+            Self::Pca9685(channel) => {
+                let pca9685 = pca9685.ok_or_else(|| eyre!("PCA9685 is not available"))?;
+                let off = (duty * 4095.0) as u16;
+                pca9685
+                    .set_channel_on_off(*channel, 0, off)
+                    .map_err(|e| eyre!("Error writing to PCA9685: {:?}", e))
+            }
+            Self::Software(pwm) => {
+                pwm.set_duty(duty);
+                Ok(())
+            }
+        }
+    }
+    pub fn pin(&self) -> Option<&GpioPin> {
+        match self {
+            Self::Software(pwm) => Some(&pwm.pin),
+            _ => None,
+        }
+    }
+}
+
+pub fn pca9685_channel(channel: u8) -> Result<Channel> {
+    match channel {
+        0 => Ok(Channel::C0),
+        1 => Ok(Channel::C1),
+        2 => Ok(Channel::C2),
+        3 => Ok(Channel::C3),
+        4 => Ok(Channel::C4),
+        5 => Ok(Channel::C5),
+        6 => Ok(Channel::C6),
+        7 => Ok(Channel::C7),
+        8 => Ok(Channel::C8),
+        9 => Ok(Channel::C9),
+        10 => Ok(Channel::C10),
+        11 => Ok(Channel::C11),
+        12 => Ok(Channel::C12),
+        13 => Ok(Channel::C13),
+        14 => Ok(Channel::C14),
+        15 => Ok(Channel::C15),
+        _ => Err(eyre!("Invalid PCA9685 channel {}", channel)),
+    }
+}
+
+/// Hardware PWM exposed through `/sys/class/pwm`
+pub struct SysfsPwm {
+    path: PathBuf,
+    period_ns: u64,
+}
+impl SysfsPwm {
+    fn new(chip: u32, channel: u32, frequency: u32) -> Result<Self> {
+        let chip_path = PathBuf::from(format!("/sys/class/pwm/pwmchip{}", chip));
+        let path = chip_path.join(format!("pwm{}", channel));
+        if !path.exists() {
+            std::fs::write(chip_path.join("export"), channel.to_string())?;
+        }
+        let period_ns = 1_000_000_000 / frequency.max(1) as u64;
+        std::fs::write(path.join("duty_cycle"), "0")?;
+        std::fs::write(path.join("period"), period_ns.to_string())?;
+        std::fs::write(path.join("enable"), "1")?;
+        debug!("Exported hardware PWM {:?} with period {}ns", path, period_ns);
+        Ok(Self { path, period_ns })
+    }
+    fn set_duty(&self, duty: f32) -> Result<()> {
+        let duty_ns = (self.period_ns as f32 * duty) as u64;
+        std::fs::write(self.path.join("duty_cycle"), duty_ns.to_string())?;
+        Ok(())
+    }
+}
+
+/// PWM generated by toggling a GPIO from a background task.
+/// Only suitable for low frequencies, the timing is as good as the tokio timer.
+pub struct SoftwarePwm {
+    pin: GpioPin,
+    duty: watch::Sender<f32>,
+}
+impl SoftwarePwm {
+    fn spawn(pin: GpioPin, frequency: u32) -> Self {
+        let (duty, mut duty_rx) = watch::channel(0.0f32);
+        let period = Duration::from_secs_f32(1.0 / frequency.max(1) as f32);
+        let task_pin = pin.clone();
+        tokio::spawn(async move {
+            let pin = task_pin;
+            // The pin is exported and its direction set by LocalConnections,
+            // don't touch it until the first duty cycle is written.
+            if duty_rx.changed().await.is_err() {
+                return;
+            }
+            loop {
+                let duty = *duty_rx.borrow();
+                let result = if duty <= 0.0 || duty >= 1.0 {
+                    let result = pin.set_value((duty >= 1.0) as u8);
+                    if duty_rx.changed().await.is_err() {
+                        break;
+                    }
+                    result
+                } else {
+                    let high = period.mul_f32(duty);
+                    let result = pin.set_value(1);
+                    sleep(high).await;
+                    let result = result.and(pin.set_value(0));
+                    sleep(period - high).await;
+                    result
+                };
+                if let Err(e) = result {
+                    error!("Error toggling software PWM pin {:?}: {}", pin, e);
+                }
+            }
+        });
+        Self { pin, duty }
+    }
+    fn set_duty(&self, duty: f32) {
+        self.duty.send_replace(duty);
+    }
+}
@@ -0,0 +1,63 @@
+use serde::{Deserialize, Serialize};
+
+/// Converts raw ADC counts into engineering units
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Calibration {
+    Linear { gain: f64, offset: f64 },
+    /// Coefficients from the constant term upwards
+    Polynomial { coefficients: Vec<f64> },
+}
+impl Calibration {
+    pub fn apply(&self, raw: u16) -> f64 {
+        let raw = raw as f64;
+        match self {
+            Self::Linear { gain, offset } => raw * gain + offset,
+            Self::Polynomial { coefficients } => coefficients
+                .iter()
+                .rev()
+                .fold(0.0, |value, coefficient| value * raw + coefficient),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn linear() {
+        let calibration = Calibration::Linear {
+            gain: 0.5,
+            offset: -10.0,
+        };
+        assert_eq!(calibration.apply(0), -10.0);
+        assert_eq!(calibration.apply(100), 40.0);
+        assert_eq!(calibration.apply(u16::MAX), 32757.5);
+    }
+
+    #[test]
+    fn polynomial_starts_from_the_constant_term() {
+        let calibration = Calibration::Polynomial {
+            coefficients: vec![1.0, 2.0, 3.0],
+        };
+        assert_eq!(calibration.apply(0), 1.0);
+        assert_eq!(calibration.apply(2), 1.0 + 4.0 + 12.0);
+    }
+
+    #[test]
+    fn empty_polynomial_is_zero() {
+        let calibration = Calibration::Polynomial {
+            coefficients: vec![],
+        };
+        assert_eq!(calibration.apply(1234), 0.0);
+    }
+
+    #[test]
+    fn parses_either_form_from_config() {
+        let linear: Calibration = toml::from_str("gain = 2.0\noffset = 1.0").unwrap();
+        assert_eq!(linear.apply(3), 7.0);
+        let polynomial: Calibration = toml::from_str("coefficients = [0.0, 0.0, 1.0]").unwrap();
+        assert_eq!(polynomial.apply(3), 9.0);
+    }
+}
@@ -1,150 +1,448 @@
-use crate::config::{Config, Handler};
-use crate::local::{LocalRequest, LocalResponse};
-use crate::pad::{PadRequest, PadResponse};
+use crate::battery::{BatteryLevel, BATTERY_TOPIC};
+use crate::backend::Backends;
+use crate::config::Config;
+use crate::drive::{twist_requests, ODOMETRY_TOPIC};
+use crate::imu::IMU_TOPIC;
+use crate::motor::{scale_motor_request, MotorTarget};
+use crate::servo::ServoCommand;
+use crate::status::SystemStatus;
+use crate::telemetry::Telemetry;
 use eyre::Result;
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
+use spine_client::{RequestEnvelope, ResponseEnvelope, PROTOCOL_VERSION};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{unix::SocketAddr, UnixStream};
+use tokio::net::unix::{OwnedWriteHalf, SocketAddr};
+use tokio::net::UnixStream;
+use tokio::sync::{mpsc, oneshot, watch, Notify};
 use tracing::{debug, error, info, warn};
 
-#[derive(Serialize, Deserialize, Debug)]
-pub enum HardwareRequest {
-    MotorWrite { motor: String, command: Vec<u8> },
-    ServoWrite { servo: String, position: u16, duty: Option<u16>, start: Option<u16> },
-    EncoderRead { encoder: String },
-    SwitchRead { switch: String },
-    LedWrite { led: String, state: u8 },
-    EncoderReset,
-    SensorRead,
+pub use spine_client::{HardwareRequest, HardwareResponse};
+
+/// Senders to the hardware and to spine's own tasks, shared by every connection
+#[derive(Clone)]
+pub struct Channels {
+    pub backends: Arc<Backends>,
+    pub send_to_ramp: mpsc::Sender<MotorTarget>,
+    pub send_to_sweep: mpsc::Sender<ServoCommand>,
+    pub status: Arc<watch::Sender<SystemStatus>>,
+    /// Targets of the joint controllers
+    pub joints: Arc<HashMap<String, watch::Sender<Option<i64>>>>,
+    /// Targets of the wheel velocity controllers, in counts per second
+    pub wheels: Arc<HashMap<String, watch::Sender<Option<f32>>>>,
+    pub reset_odometry: Arc<Notify>,
+    pub telemetry: Arc<Telemetry>,
 }
-#[derive(Serialize, Deserialize, Debug)]
-pub enum HardwareResponse {
-    EncoderValue(i32),
-    SensorValue(u16),
-    SwitchOn(bool),
-    Ok,
+
+/// A request as clients send it, in an envelope or bare
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Incoming {
+    Tagged(RequestEnvelope),
+    Bare(HardwareRequest),
 }
-impl HardwareResponse {
-    pub fn from_pad_response(pr: PadResponse) -> Self {
-        match pr {
-            PadResponse::EncoderValue(v) => Self::EncoderValue(v),
-            PadResponse::SensorValue(v) => Self::SensorValue(v),
-            PadResponse::Ok => Self::Ok,
-        }
-    }
-    pub fn from_local_response(lr: LocalResponse) -> Self {
-        match lr {
-            LocalResponse::SwitchOn(v) => Self::SwitchOn(v),
-            LocalResponse::Ok => Self::Ok,
-        }
-    }
+
+/// A response written once a task of its own is done: a value published on a subscription, or
+/// the end of a servo move
+struct Deferred {
+    id: Option<u64>,
+    device: Option<String>,
+    response: HardwareResponse,
 }
 
 pub async fn handle_stream(
     config: &Config,
     accept_result: (UnixStream, SocketAddr),
-    mut send_to_pad: tokio::sync::mpsc::Sender<PadRequest>,
-    mut send_to_local: tokio::sync::mpsc::Sender<LocalRequest>,
+    mut channels: Channels,
 ) -> Result<()> {
-    let (mut stream, _addr) = accept_result;
+    let (stream, _addr) = accept_result;
     info!("New connection: {:?}", stream);
+    let (mut reader, mut writer) = stream.into_split();
+    let mut version = config.system.protocol_version;
+    let (send_deferred, mut recv_deferred) = mpsc::channel::<Deferred>(16);
+    let mut subscriptions: HashMap<String, tokio::task::JoinHandle<()>> = HashMap::new();
     let mut msg = vec![0; 1024];
     loop {
-        let n = stream.read(&mut msg).await?;
+        let n = tokio::select! {
+            n = reader.read(&mut msg) => n?,
+            Some(Deferred { id, device, response }) = recv_deferred.recv() => {
+                write_response(&mut writer, version, id, device.as_deref(), response).await?;
+                continue;
+            }
+        };
         if n == 0 {
             info!("Connection closed");
             break;
         }
         debug!("Read {} bytes", n);
-        let hw_req_stream =
-            serde_json::Deserializer::from_slice(&msg).into_iter::<HardwareRequest>();
+        let hw_req_stream = serde_json::Deserializer::from_slice(&msg[..n]).into_iter::<Incoming>();
         for hw_req_unchecked in hw_req_stream {
-            if hw_req_unchecked.is_err() {
-                warn!("Error decoding message");
-                continue;
-            }
-            let hw_req = hw_req_unchecked.unwrap();
+            let (id, hw_req) = match hw_req_unchecked {
+                Ok(Incoming::Tagged(RequestEnvelope { id, request })) => (Some(id), request),
+                Ok(Incoming::Bare(request)) => (None, request),
+                Err(_) => {
+                    warn!("Error decoding message");
+                    continue;
+                }
+            };
             info!("Successfully received HardwareRequest message");
             debug!("Message: {:?}", hw_req);
+            let device = hw_req.device().map(str::to_string);
 
-            match handle_request(config, hw_req, &mut send_to_pad, &mut send_to_local).await {
-                HardwareResponse::EncoderValue(v) => {
-                    let encoded_resp = serde_json::to_string(&v)?;
-                    info!("Received encoder value, writing back to client");
-                    debug!("Encoded response: {:?}", encoded_resp);
-                    let resp = encoded_resp.as_bytes();
-                    stream.writable().await?;
-
-                    if let Err(e) = stream.write_all(resp).await {
-                        error!("Error writing to stream: {}", e);
+            let response = match hw_req {
+                HardwareRequest::Subscribe { topic } => {
+                    info!("Subscribing to {}", topic);
+                    let mut published = channels.telemetry.subscribe(&topic);
+                    let send_deferred = send_deferred.clone();
+                    let forwarded_topic = topic.clone();
+                    let forward = tokio::spawn(async move {
+                        while published.changed().await.is_ok() {
+                            let value = published.borrow_and_update().clone();
+                            if let Some(response) = value {
+                                let published = Deferred {
+                                    id: None,
+                                    device: Some(forwarded_topic.clone()),
+                                    response,
+                                };
+                                if send_deferred.send(published).await.is_err() {
+                                    break;
+                                }
+                            }
+                        }
+                    });
+                    if let Some(previous) = subscriptions.insert(topic, forward) {
+                        previous.abort();
                     }
+                    HardwareResponse::Ok
                 }
-                HardwareResponse::SensorValue(v) => {
-                    let encoded_resp = serde_json::to_string(&v)?;
-                    info!("Received sensor value, writing back to client");
-                    debug!("Encoded response: {:?}", encoded_resp);
-                    let resp = encoded_resp.as_bytes();
-                    stream.writable().await?;
-
-                    if let Err(e) = stream.write_all(resp).await {
-                        error!("Error writing to stream: {}", e);
+                HardwareRequest::Unsubscribe { topic } => {
+                    info!("Unsubscribing from {}", topic);
+                    if let Some(forward) = subscriptions.remove(&topic) {
+                        forward.abort();
                     }
+                    HardwareResponse::Ok
                 }
-                HardwareResponse::SwitchOn(v) => {
-                    let encoded_resp = serde_json::to_string(&v)?;
-                    info!("Received switch value, writing back to client");
-                    debug!("Encoded response: {:?}", encoded_resp);
-                    let resp = encoded_resp.as_bytes();
-                    stream.writable().await?;
-
-                    if let Err(e) = stream.write_all(resp).await {
-                        error!("Error writing to stream: {}", e);
+                HardwareRequest::SetProtocolVersion { version: requested } => {
+                    if (1..=PROTOCOL_VERSION).contains(&requested) {
+                        info!("Switching to protocol version {}", requested);
+                        version = requested;
+                        HardwareResponse::Ok
+                    } else {
+                        refuse(format!("Unknown protocol version {}, keeping {}", requested, version))
                     }
                 }
-                HardwareResponse::Ok => {}
-            }
+                // Replied to once the sweep is over, other requests are handled meanwhile
+                HardwareRequest::ServoMove { servo, target, max_velocity } => {
+                    let done = start_servo_move(&channels, servo, target, max_velocity).await;
+                    let send_deferred = send_deferred.clone();
+                    tokio::spawn(async move {
+                        let response = done.await;
+                        let _ = send_deferred.send(Deferred { id, device, response }).await;
+                    });
+                    continue;
+                }
+                hw_req => handle_request(config, hw_req, &mut channels).await,
+            };
+            write_response(&mut writer, version, id, device.as_deref(), response).await?;
+        }
+    }
+    subscriptions.values().for_each(|forward| forward.abort());
+    Ok(())
+}
+/// Writes a response as protocol `version` does, see `PROTOCOL_VERSION`
+async fn write_response(
+    writer: &mut OwnedWriteHalf,
+    version: u8,
+    id: Option<u64>,
+    device: Option<&str>,
+    response: HardwareResponse,
+) -> Result<()> {
+    let encoded_resp = if version >= 2 {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        let envelope = ResponseEnvelope {
+            id,
+            device: device.map(str::to_string),
+            timestamp,
+            response,
+        };
+        serde_json::to_string(&envelope)? + "\n"
+    } else {
+        match legacy_value(&response)? {
+            Some(value) => value,
+            None => return Ok(()),
         }
+    };
+    info!("Writing back response to client");
+    debug!("Encoded response: {:?}", encoded_resp);
+    writer.writable().await?;
+    if let Err(e) = writer.write_all(encoded_resp.as_bytes()).await {
+        error!("Error writing to stream: {}", e);
     }
     Ok(())
 }
-async fn handle_request(
+/// The bare value carried by a response as protocol version 1 writes it, `Ok` and errors aren't
+/// written
+fn legacy_value(response: &HardwareResponse) -> serde_json::Result<Option<String>> {
+    Ok(Some(match response {
+        HardwareResponse::EncoderValue(v) => serde_json::to_string(v)?,
+        HardwareResponse::Measurement { value, unit } => {
+            serde_json::to_string(&serde_json::json!({ "value": value, "unit": unit }))?
+        }
+        HardwareResponse::SensorValue(v) => serde_json::to_string(v)?,
+        HardwareResponse::SwitchOn(v) => serde_json::to_string(v)?,
+        HardwareResponse::GpioValue(v) => serde_json::to_string(v)?,
+        HardwareResponse::MoveComplete(v) => serde_json::to_string(v)?,
+        HardwareResponse::Odometry(v) => serde_json::to_string(v)?,
+        HardwareResponse::Battery(v) => serde_json::to_string(v)?,
+        HardwareResponse::Readings(v) => serde_json::to_string(v)?,
+        HardwareResponse::Imu(v) => serde_json::to_string(v)?,
+        HardwareResponse::Status(v) => serde_json::to_string(v)?,
+        HardwareResponse::Config(v) => serde_json::to_string(v)?,
+        HardwareResponse::Error(_) | HardwareResponse::Ok => return Ok(None),
+    }))
+}
+/// Logs why a request wasn't carried out, and tells the client
+fn refuse(reason: String) -> HardwareResponse {
+    warn!("{}", reason);
+    HardwareResponse::Error(reason)
+}
+pub async fn handle_request(
     config: &Config,
     req: HardwareRequest,
-    send_to_pad: &mut tokio::sync::mpsc::Sender<PadRequest>,
-    send_to_local: &mut tokio::sync::mpsc::Sender<LocalRequest>,
+    channels: &mut Channels,
 ) -> HardwareResponse {
-    match config.resolve(&req) {
-        Some(Handler::Pad(port)) => {
-            let wait_for_response = matches!(req, HardwareRequest::EncoderRead { .. }) || matches!(req, HardwareRequest::SensorRead { .. });
-            debug!("Sending request to pad");
-            let (recv_from_pad, pad_req) = PadRequest::from_hardware_request(port, req);
-            send_to_pad.send(pad_req).await.unwrap();
-            if wait_for_response {
-                let pad_resp = recv_from_pad.await.unwrap();
-                info!("Heard back from PAD, writing back HardwareResponse");
-                debug!("Received pad response: {:?}", pad_resp);
-                HardwareResponse::from_pad_response(pad_resp)
+    let req = match (&config.battery, channels.status.borrow().battery) {
+        (Some(battery), BatteryLevel::Low) => scale_motor_request(config, req, battery.warn_scale),
+        _ => req,
+    };
+    match req {
+        HardwareRequest::EStop { engaged } => {
+            channels.status.send_modify(|status| status.estop = engaged);
+            if engaged {
+                warn!("E-stop engaged, stopping all motors");
+                stop_all_motors(config, channels).await;
             } else {
+                info!("E-stop released");
+            }
+            HardwareResponse::Ok
+        }
+        HardwareRequest::MotorWrite { .. } | HardwareRequest::MotorSet { .. }
+            if channels.status.borrow().estop =>
+        {
+            refuse("E-stop engaged, ignoring motor write".to_string())
+        }
+        HardwareRequest::MotorWrite { .. } | HardwareRequest::MotorSet { .. }
+            if channels.status.borrow().battery == BatteryLevel::Cutoff =>
+        {
+            refuse("Battery below cutoff, ignoring motor write".to_string())
+        }
+        HardwareRequest::MotorWrite { .. } | HardwareRequest::MotorSet { .. }
+            if channels.status.borrow().tilted =>
+        {
+            refuse("Rover tilted, ignoring motor write".to_string())
+        }
+        HardwareRequest::MotorWrite { ref motor, ref command }
+            if config.max_acceleration(motor).is_some() && config.command_speed(motor, command).is_some() =>
+        {
+            let target = MotorTarget {
+                speed: config.command_speed(motor, command).unwrap_or_default(),
+                motor: motor.clone(),
+            };
+            channels.send_to_ramp.send(target).await.unwrap();
+            HardwareResponse::Ok
+        }
+        HardwareRequest::MotorSet { motor, speed } if config.max_acceleration(&motor).is_some() => {
+            channels.send_to_ramp.send(MotorTarget { motor, speed }).await.unwrap();
+            HardwareResponse::Ok
+        }
+        HardwareRequest::ServoWrite { .. } | HardwareRequest::ServoSetAngle { .. } => {
+            let req = config.lower_request(req);
+            channels.send_to_sweep.send(ServoCommand::Write(req)).await.unwrap();
+            HardwareResponse::Ok
+        }
+        // Connections don't wait for the sweep here, see `handle_stream`
+        HardwareRequest::ServoMove { servo, target, max_velocity } => {
+            start_servo_move(channels, servo, target, max_velocity).await.await
+        }
+        HardwareRequest::JointSetTarget { joint, counts } => match channels.joints.get(&joint) {
+            Some(target) => {
+                target.send_replace(Some(counts));
+                HardwareResponse::Ok
+            }
+            None => refuse(format!("No joint named {}", joint)),
+        },
+        HardwareRequest::JointRelease { joint } => match channels.joints.get(&joint) {
+            Some(target) => {
+                target.send_replace(None);
                 HardwareResponse::Ok
             }
+            None => refuse(format!("No joint named {}", joint)),
+        },
+        HardwareRequest::WheelSetVelocity { wheel, velocity, unit } => {
+            let counts_per_second = config.wheel_counts_per_second(&wheel, velocity, unit);
+            match (channels.wheels.get(&wheel), counts_per_second) {
+                (Some(target), Some(counts_per_second)) => {
+                    target.send_replace(Some(counts_per_second));
+                    HardwareResponse::Ok
+                }
+                (Some(_), None) => {
+                    refuse(format!("Wheel {} has no counts_per_metre and isn't in the drive", wheel))
+                }
+                (None, _) => refuse(format!("No wheel named {}", wheel)),
+            }
         }
-        Some(Handler::System) => {
-            let wait_for_response = matches!(req, HardwareRequest::SwitchRead { .. });
-            debug!("Sending request to local system");
-            let (recv_from_local, local_req) = LocalRequest::from_hardware_request(req);
-            send_to_local.send(local_req).await.unwrap();
-            if wait_for_response {
-                let local_resp = recv_from_local.await.unwrap();
-                info!("Heard back from local system, writing back HardwareResponse");
-                debug!("Received local response: {:?}", local_resp);
-                HardwareResponse::from_local_response(local_resp)
-            } else {
+        HardwareRequest::WheelRelease { wheel } => match channels.wheels.get(&wheel) {
+            Some(target) => {
+                target.send_replace(None);
                 HardwareResponse::Ok
             }
+            None => refuse(format!("No wheel named {}", wheel)),
+        },
+        HardwareRequest::DriveTwist { linear, angular } => match &config.drive {
+            Some(drive) => {
+                for req in twist_requests(drive, linear, angular) {
+                    Box::pin(handle_request(config, req, channels)).await;
+                }
+                HardwareResponse::Ok
+            }
+            None => refuse("No drive configured".to_string()),
+        },
+        HardwareRequest::OdometryRead => {
+            channels
+                .telemetry
+                .latest(ODOMETRY_TOPIC)
+                .unwrap_or_else(|| refuse("No odometry has been published".to_string()))
         }
-        None => {
-            warn!("No handler found");
+        HardwareRequest::ResetOdometry => {
+            channels.reset_odometry.notify_one();
             HardwareResponse::Ok
         }
+        HardwareRequest::BatteryRead => channels
+            .telemetry
+            .latest(BATTERY_TOPIC)
+            .unwrap_or_else(|| refuse("No battery reading has been published".to_string())),
+        HardwareRequest::ImuRead => channels
+            .telemetry
+            .latest(IMU_TOPIC)
+            .unwrap_or_else(|| refuse("No IMU reading has been published".to_string())),
+        HardwareRequest::StatusRead => HardwareResponse::Status(*channels.status.borrow()),
+        HardwareRequest::ConfigDump => match serde_json::to_value(config) {
+            Ok(value) => HardwareResponse::Config(value),
+            Err(e) => {
+                error!("Could not serialize the config: {}", e);
+                HardwareResponse::Error(format!("Could not serialize the config: {}", e))
+            }
+        },
+        HardwareRequest::I2cRead { device } if config.system.i2c.contains_key(&device) => {
+            channels
+                .telemetry
+                .latest(&device)
+                .unwrap_or_else(|| refuse(format!("No readings of {} have been published", device)))
+        }
+        HardwareRequest::I2cRead { device } => refuse(format!("No I2C device named {}", device)),
+        HardwareRequest::SensorRead { sensor } => {
+            let read = HardwareRequest::SensorRead { sensor: sensor.clone() };
+            let raw = channels.backends.dispatch(read).await;
+            config.calibrate_sensor(&sensor, raw)
+        }
+        HardwareRequest::EncoderRead { encoder } => {
+            let read = HardwareRequest::EncoderRead { encoder: encoder.clone() };
+            let counts = channels.backends.dispatch(read).await;
+            config.scale_encoder(&encoder, counts)
+        }
+        req => {
+            channels.backends.dispatch(config.lower_request(req)).await
+        }
+    }
+}
+/// Starts sweeping a servo, returning the reply to the move for once the sweep is over
+async fn start_servo_move(
+    channels: &Channels,
+    servo: String,
+    target: u16,
+    max_velocity: f32,
+) -> impl Future<Output = HardwareResponse> {
+    let (done, recv_from_sweep) = oneshot::channel();
+    let command = ServoCommand::Move { servo, target, max_velocity, done };
+    channels.send_to_sweep.send(command).await.unwrap();
+    async move {
+        recv_from_sweep.await.unwrap_or_else(|_| {
+            error!("Servo move was dropped");
+            HardwareResponse::Error("Servo move was dropped".to_string())
+        })
+    }
+}
+/// Releases every joint and wheel and stops every motor, bypassing ramps
+pub async fn stop_all_motors(config: &Config, channels: &mut Channels) {
+    channels.joints.values().for_each(|target| {
+        target.send_replace(None);
+    });
+    channels.wheels.values().for_each(|target| {
+        target.send_replace(None);
+    });
+    for motor in config.motor_names() {
+        let stop = HardwareRequest::MotorWrite { motor, command: vec![0] };
+        channels.backends.dispatch(config.lower_motor_request(stop)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{Backend, DeviceKind};
+    use crate::motor::{MotorDriver, MotorTarget};
+
+    /// Owns a motor and passes on every command written to it
+    struct Recorder(mpsc::UnboundedSender<Vec<u8>>);
+    impl Backend for Recorder {
+        fn devices(&self) -> Vec<(DeviceKind, String)> {
+            vec![(DeviceKind::Motor, "arm".to_string())]
+        }
+        async fn write_motor(&mut self, _motor: &str, command: &[u8]) -> Result<()> {
+            self.0.send(command.to_vec()).unwrap();
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn smartelex_motors_are_stopped() {
+        let config: Config = toml::from_str(
+            r#"
+            [pad.motors]
+            arm = { port = 3, channel = 2, driver = "smartelex" }
+            [pad.encoders]
+            [pad.servos]
+            [system]
+            pca9685_path = "/dev/i2c-1"
+            [system.motors]
+            [system.limit_switches]
+            [system.status_leds]
+            [system.servos]
+            "#,
+        )
+        .unwrap();
+        let (send_written, mut written) = mpsc::unbounded_channel();
+        let mut backends = Backends::default();
+        backends.spawn("test", Recorder(send_written));
+        let (send_to_ramp, _) = mpsc::channel::<MotorTarget>(1);
+        let (send_to_sweep, _) = mpsc::channel(1);
+        let (status, _) = watch::channel(SystemStatus::default());
+        let mut channels = Channels {
+            backends: Arc::new(backends),
+            send_to_ramp,
+            send_to_sweep,
+            status: Arc::new(status),
+            joints: Arc::default(),
+            wheels: Arc::default(),
+            reset_odometry: Arc::default(),
+            telemetry: Arc::default(),
+        };
+        stop_all_motors(&config, &mut channels).await;
+        assert_eq!(written.recv().await.unwrap(), MotorDriver::Smartelex.stop(2));
     }
 }
@@ -0,0 +1,265 @@
+use crate::backend::Backends;
+use crate::config::Config;
+use crate::server::{HardwareRequest, HardwareResponse};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{Duration, MissedTickBehavior};
+use tracing::{debug, trace};
+
+const SWEEP_TICK: Duration = Duration::from_millis(20);
+
+/// Converts a pulse width into the number of ticks it's high for, in a PWM running at `pwm_freq`
+/// with `pwm_adc_max_value` ticks per period.
+pub fn microseconds_to_ticks(microseconds: u16, pwm_freq: u32, pwm_adc_max_value: u16) -> u16 {
+    let microseconds = microseconds as f32;
+    let microseconds = microseconds / 1_000_000.0;
+    let microseconds = microseconds * pwm_freq as f32;
+    let microseconds = microseconds * pwm_adc_max_value as f32;
+    microseconds as u16
+}
+
+/// Endpoints of a servo. Angles are measured from neutral, and span `range_deg` in total.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(default)]
+pub struct ServoCalibration {
+    pub min_us: u16,
+    pub max_us: u16,
+    pub neutral_us: u16,
+    pub range_deg: f32,
+    pub invert: bool,
+}
+impl Default for ServoCalibration {
+    fn default() -> Self {
+        Self {
+            min_us: 1000,
+            max_us: 2000,
+            neutral_us: 1500,
+            range_deg: 180.0,
+            invert: false,
+        }
+    }
+}
+impl ServoCalibration {
+    /// Pulse width for `degrees` from neutral, clamped to the calibrated range
+    pub fn angle_to_microseconds(&self, degrees: f32) -> u16 {
+        let half_range = self.range_deg / 2.0;
+        let degrees = if self.invert { -degrees } else { degrees };
+        let fraction = (degrees / half_range).clamp(-1.0, 1.0);
+        let neutral = self.neutral_us as f32;
+        let microseconds = if fraction >= 0.0 {
+            neutral + fraction * (self.max_us as f32 - neutral)
+        } else {
+            neutral + fraction * (neutral - self.min_us as f32)
+        };
+        self.clamp(microseconds.round() as u16)
+    }
+    pub fn clamp(&self, microseconds: u16) -> u16 {
+        microseconds.max(self.min_us).min(self.max_us)
+    }
+}
+
+#[derive(Debug)]
+pub enum ServoCommand {
+    /// A `ServoWrite` to write immediately, stopping any sweep of the servo
+    Write(HardwareRequest),
+    /// Sweep to `target` microseconds at no more than `max_velocity` microseconds per second.
+    /// `done` gets `MoveComplete` with the position the servo stopped at, when it reaches the
+    /// target or is preempted, or `Error` if a write of the sweep failed.
+    Move {
+        servo: String,
+        target: u16,
+        max_velocity: f32,
+        done: oneshot::Sender<HardwareResponse>,
+    },
+}
+
+struct Sweep {
+    target: u16,
+    max_velocity: f32,
+    done: oneshot::Sender<HardwareResponse>,
+}
+
+/// Writes servo positions, sweeping servos towards their targets on a fixed tick.
+/// A servo that has never been written to jumps straight to its first target, as its position is
+/// unknown.
+pub async fn sweep_servos(
+    config: Arc<Config>,
+    mut commands: mpsc::Receiver<ServoCommand>,
+    backends: Arc<Backends>,
+) {
+    let mut positions: HashMap<String, f32> = HashMap::new();
+    let mut sweeps: HashMap<String, Sweep> = HashMap::new();
+    let mut interval = tokio::time::interval(SWEEP_TICK);
+    // Ticks aren't waited for while nothing sweeps, they mustn't all fire at once when one starts
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            command = commands.recv() => match command {
+                Some(ServoCommand::Write(req)) => {
+                    if let HardwareRequest::ServoWrite { servo, position, duty, .. } = &req {
+                        if let Some(sweep) = sweeps.remove(servo) {
+                            sweep.done.send(HardwareResponse::MoveComplete(positions[servo] as u16)).ok();
+                        }
+                        // A raw duty can't be turned back into a position
+                        match duty {
+                            None => positions.insert(servo.clone(), *position as f32),
+                            Some(_) => positions.remove(servo),
+                        };
+                    }
+                    backends.dispatch(req).await;
+                }
+                Some(ServoCommand::Move { servo, target, max_velocity, done }) => {
+                    debug!("Sweeping {} to {} at {}us/s", servo, target, max_velocity);
+                    let req = config.lower_servo_request(HardwareRequest::ServoWrite {
+                        servo: servo.clone(),
+                        position: target,
+                        duty: None,
+                        start: None,
+                    });
+                    let target = match req {
+                        HardwareRequest::ServoWrite { position, .. } => position,
+                        _ => target,
+                    };
+                    if let Some(sweep) = sweeps.remove(&servo) {
+                        sweep.done.send(HardwareResponse::MoveComplete(positions[&servo] as u16)).ok();
+                    }
+                    match positions.entry(servo) {
+                        Entry::Occupied(entry) => {
+                            sweeps.insert(entry.key().clone(), Sweep { target, max_velocity, done });
+                        }
+                        Entry::Vacant(entry) => {
+                            let response = match backends.dispatch(req).await {
+                                error @ HardwareResponse::Error(_) => error,
+                                _ => {
+                                    entry.insert(target as f32);
+                                    HardwareResponse::MoveComplete(target)
+                                }
+                            };
+                            done.send(response).ok();
+                        }
+                    }
+                }
+                None => break,
+            },
+            _ = interval.tick(), if !sweeps.is_empty() => {
+                let mut finished = vec![];
+                for (servo, sweep) in sweeps.iter() {
+                    let position = positions.get_mut(servo).unwrap();
+                    let max_step = match sweep.max_velocity {
+                        v if v > 0.0 => v * SWEEP_TICK.as_secs_f32(),
+                        _ => f32::INFINITY,
+                    };
+                    *position += (sweep.target as f32 - *position).clamp(-max_step, max_step);
+                    let reached = (*position - sweep.target as f32).abs() < 0.5;
+                    if reached {
+                        *position = sweep.target as f32;
+                    }
+                    trace!("Swept {} to {}", servo, position);
+                    let req = HardwareRequest::ServoWrite {
+                        servo: servo.clone(),
+                        position: position.round() as u16,
+                        duty: None,
+                        start: None,
+                    };
+                    // A failed write ends the sweep, rather than reporting a move that never happened
+                    let response = match backends.dispatch(req).await {
+                        error @ HardwareResponse::Error(_) => error,
+                        _ if reached => HardwareResponse::MoveComplete(sweep.target),
+                        _ => continue,
+                    };
+                    finished.push((servo.clone(), response));
+                }
+                for (servo, response) in finished {
+                    let sweep = sweeps.remove(&servo).unwrap();
+                    debug!("{} finished sweeping to {}: {:?}", servo, sweep.target, response);
+                    sweep.done.send(response).ok();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ASYMMETRIC: ServoCalibration = ServoCalibration {
+        min_us: 900,
+        max_us: 2100,
+        neutral_us: 1400,
+        range_deg: 90.0,
+        invert: false,
+    };
+
+    #[test]
+    fn neutral_and_endpoints() {
+        assert_eq!(ASYMMETRIC.angle_to_microseconds(0.0), 1400);
+        assert_eq!(ASYMMETRIC.angle_to_microseconds(45.0), 2100);
+        assert_eq!(ASYMMETRIC.angle_to_microseconds(-45.0), 900);
+    }
+
+    #[test]
+    fn each_side_scales_to_its_own_endpoint() {
+        assert_eq!(ASYMMETRIC.angle_to_microseconds(22.5), 1750);
+        assert_eq!(ASYMMETRIC.angle_to_microseconds(-22.5), 1150);
+    }
+
+    #[test]
+    fn angles_past_the_range_are_clamped() {
+        assert_eq!(ASYMMETRIC.angle_to_microseconds(1000.0), 2100);
+        assert_eq!(ASYMMETRIC.angle_to_microseconds(-1000.0), 900);
+        assert_eq!(ASYMMETRIC.clamp(500), 900);
+        assert_eq!(ASYMMETRIC.clamp(3000), 2100);
+        assert_eq!(ASYMMETRIC.clamp(1234), 1234);
+    }
+
+    #[test]
+    fn invert_mirrors_about_neutral() {
+        let inverted = ServoCalibration {
+            invert: true,
+            ..ServoCalibration::default()
+        };
+        assert_eq!(inverted.angle_to_microseconds(90.0), 1000);
+        assert_eq!(inverted.angle_to_microseconds(-45.0), 1750);
+    }
+
+    #[test]
+    fn ticks_of_a_pulse() {
+        // 1.5ms of a 60Hz period, with 4095 ticks per period
+        assert_eq!(microseconds_to_ticks(1500, 60, 4095), 368);
+        assert_eq!(microseconds_to_ticks(0, 60, 4095), 0);
+    }
+
+    #[tokio::test]
+    async fn failed_moves_reply_with_an_error() {
+        let config: Config = toml::from_str(
+            r#"
+            [pad.motors]
+            [pad.encoders]
+            [pad.servos]
+            [system]
+            pca9685_path = "/dev/i2c-1"
+            [system.motors]
+            [system.limit_switches]
+            [system.status_leds]
+            [system.servos]
+            "#,
+        )
+        .unwrap();
+        // No backend owns the servo, so writing it fails
+        let (send_to_sweep, commands) = mpsc::channel(1);
+        tokio::spawn(sweep_servos(Arc::new(config), commands, Arc::new(Backends::default())));
+        let (done, moved) = oneshot::channel();
+        let command = ServoCommand::Move {
+            servo: "camera_pan".to_string(),
+            target: 1500,
+            max_velocity: 100.0,
+            done,
+        };
+        send_to_sweep.send(command).await.unwrap();
+        assert!(matches!(moved.await.unwrap(), HardwareResponse::Error(_)));
+    }
+}
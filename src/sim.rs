@@ -0,0 +1,243 @@
+use crate::backend::{Backend, DeviceKind};
+use crate::config::Config;
+use crate::motor::{MotorCommand, MotorDriver};
+use eyre::{eyre, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::time::Instant;
+use tracing::{debug, info, trace};
+
+/// Model of the rover behind a simulated PAD
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SimConfig {
+    /// Largest error added to every encoder reading, in counts
+    pub noise: f64,
+    pub seed: u64,
+    /// Encoders driven by a motor, by encoder name
+    pub encoders: HashMap<String, SimEncoderConfig>,
+    /// Values returned by sensors, by sensor name
+    pub sensors: HashMap<String, SimSensorConfig>,
+}
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            noise: 0.0,
+            seed: 0x5eed,
+            encoders: HashMap::new(),
+            sensors: HashMap::new(),
+        }
+    }
+}
+/// An encoder on the output shaft of a gearbox driven by `motor`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SimEncoderConfig {
+    pub motor: String,
+    /// Speed of the motor at full speed
+    #[serde(default = "default_max_rpm")]
+    pub max_rpm: f64,
+    /// Motor turns per turn of the output shaft
+    #[serde(default = "default_gear_ratio")]
+    pub gear_ratio: f64,
+    #[serde(default = "default_counts_per_revolution")]
+    pub counts_per_revolution: f64,
+}
+fn default_max_rpm() -> f64 {
+    3000.0
+}
+fn default_gear_ratio() -> f64 {
+    1.0
+}
+fn default_counts_per_revolution() -> f64 {
+    2048.0
+}
+impl SimEncoderConfig {
+    fn counts_per_second(&self, speed: f32) -> f64 {
+        speed as f64 * self.max_rpm / 60.0 / self.gear_ratio * self.counts_per_revolution
+    }
+}
+/// Either a constant reading, or readings returned in turn and repeated
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum SimSensorConfig {
+    Constant(u16),
+    Sequence(Vec<u16>),
+}
+
+/// Xorshift, enough for encoder noise without pulling in a crate
+struct Rng(u64);
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+    /// Uniform in -1.0..1.0
+    fn signed_unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 52) as f64 - 1.0
+    }
+}
+
+/// In-process stand-in for the PAD. Motors integrate into encoder counts, sensors return
+/// their scripted values and the connection never drops.
+pub struct SimPad {
+    config: SimConfig,
+    /// Port of every motor, a stop command stops every motor on the port
+    motors: HashMap<String, u8>,
+    drivers: HashMap<String, MotorDriver>,
+    servos: Vec<String>,
+    speeds: HashMap<String, f32>,
+    encoders: HashMap<String, f64>,
+    offsets: HashMap<String, i64>,
+    sensors: HashMap<String, usize>,
+    rng: Rng,
+    last_step: Instant,
+}
+impl SimPad {
+    pub fn from_config(config: &Config) -> Self {
+        let pad = &config.pad;
+        Self {
+            config: pad.sim.clone(),
+            motors: pad
+                .motors
+                .iter()
+                .map(|(name, motor)| (name.clone(), motor.port()))
+                .collect(),
+            drivers: pad
+                .motors
+                .iter()
+                .map(|(name, motor)| (name.clone(), motor.driver()))
+                .collect(),
+            servos: pad.servos.keys().cloned().collect(),
+            speeds: HashMap::new(),
+            encoders: pad
+                .encoders
+                .keys()
+                .map(|name| (name.clone(), 0.0))
+                .collect(),
+            offsets: HashMap::new(),
+            sensors: pad.sensors.keys().map(|name| (name.clone(), 0)).collect(),
+            rng: Rng(pad.sim.seed.max(1)),
+            last_step: Instant::now(),
+        }
+    }
+    /// Integrates the motor speeds since the last step into the encoders
+    fn step(&mut self) {
+        let now = Instant::now();
+        let dt = (now - self.last_step).as_secs_f64();
+        self.last_step = now;
+        for (encoder, counts) in self.encoders.iter_mut() {
+            let Some(sim) = self.config.encoders.get(encoder) else {
+                continue;
+            };
+            let speed = self.speeds.get(&sim.motor).copied().unwrap_or(0.0);
+            *counts += sim.counts_per_second(speed) * dt;
+        }
+    }
+    fn reading(&mut self, encoder: &str) -> Result<i64> {
+        let counts = *self
+            .encoders
+            .get(encoder)
+            .ok_or_else(|| eyre!("{} is not connected to the PAD", encoder))?;
+        let noise = self.rng.signed_unit() * self.config.noise;
+        Ok((counts + noise).round() as i64)
+    }
+}
+impl Backend for SimPad {
+    fn devices(&self) -> Vec<(DeviceKind, String)> {
+        let motors = self
+            .motors
+            .keys()
+            .map(|name| (DeviceKind::Motor, name.clone()));
+        let servos = self
+            .servos
+            .iter()
+            .map(|name| (DeviceKind::Servo, name.clone()));
+        let encoders = self
+            .encoders
+            .keys()
+            .map(|name| (DeviceKind::Encoder, name.clone()));
+        let sensors = self
+            .sensors
+            .keys()
+            .map(|name| (DeviceKind::Sensor, name.clone()));
+        motors
+            .chain(servos)
+            .chain(encoders)
+            .chain(sensors)
+            .collect()
+    }
+    async fn connect(&mut self) {
+        info!("Simulating the PAD");
+    }
+    async fn write_motor(&mut self, motor: &str, command: &[u8]) -> Result<()> {
+        let port = *self
+            .motors
+            .get(motor)
+            .ok_or_else(|| eyre!("{} is not connected to the PAD", motor))?;
+        self.step();
+        let driver = self.drivers.get(motor).copied().unwrap_or_default();
+        match MotorCommand::parse(driver, command)? {
+            MotorCommand::Sabertooth(0) => {
+                for (other, other_port) in &self.motors {
+                    if *other_port == port {
+                        self.speeds.insert(other.clone(), 0.0);
+                    }
+                }
+            }
+            command => {
+                self.speeds.insert(motor.to_string(), command.speed().0);
+            }
+        }
+        trace!("Simulated motor speeds: {:?}", self.speeds);
+        Ok(())
+    }
+    async fn write_servo(
+        &mut self,
+        servo: &str,
+        position: u16,
+        duty: Option<u16>,
+        _start: Option<u16>,
+    ) -> Result<()> {
+        debug!(
+            "Simulated servo {} at {}us, duty {:?}",
+            servo, position, duty
+        );
+        Ok(())
+    }
+    async fn read_encoder(&mut self, encoder: &str) -> Result<i64> {
+        self.step();
+        let offset = self.offsets.get(encoder).copied().unwrap_or(0);
+        Ok(self.reading(encoder)? - offset)
+    }
+    async fn zero_encoder(&mut self, encoder: &str) -> Result<()> {
+        self.step();
+        let counts = self.reading(encoder)?;
+        self.offsets.insert(encoder.to_string(), counts);
+        Ok(())
+    }
+    async fn reset_encoders(&mut self) -> Result<()> {
+        self.step();
+        self.encoders.values_mut().for_each(|counts| *counts = 0.0);
+        self.offsets.clear();
+        Ok(())
+    }
+    async fn read_sensor(&mut self, sensor: &str) -> Result<u16> {
+        let index = self
+            .sensors
+            .get_mut(sensor)
+            .ok_or_else(|| eyre!("{} is not connected to the PAD", sensor))?;
+        let value = match self.config.sensors.get(sensor) {
+            None => 0,
+            Some(SimSensorConfig::Constant(value)) => *value,
+            Some(SimSensorConfig::Sequence(values)) if values.is_empty() => 0,
+            Some(SimSensorConfig::Sequence(values)) => {
+                let value = values[*index % values.len()];
+                *index += 1;
+                value
+            }
+        };
+        Ok(value)
+    }
+}
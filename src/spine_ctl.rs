@@ -0,0 +1,253 @@
+// Command line client for a running spine, talking to it over its socket like any other node
+use eyre::{eyre, Result};
+use serde::Serialize;
+use serde_json::json;
+use spine_client::{BatteryLevel, Client, LedPattern, Reading, SystemStatus, DEFAULT_SOCKET};
+use std::fmt::Display;
+use std::time::Duration;
+
+const USAGE: &str = "Usage: spine-ctl [--json] [--socket <path>] [--timeout <ms>] [--interval <ms>] <command>
+
+Commands:
+  motor set <motor> <speed>           speed from -1.0 to 1.0
+  servo set <servo> <position>        position in microseconds, or degrees from neutral as e.g. 15deg
+  encoder read <encoder>
+  encoder watch <encoder>             read every --interval until interrupted
+  switch read <switch>
+  switch watch <switch>               print the switch whenever it changes
+  gpio read <pin>                     level a GPIO was last driven to, e.g. an LED on mock GPIOs
+  led set <led> <state>               on, off, solid, heartbeat, blink <hz> or pulse <count>
+  sensor read <sensor>
+  status                              PAD connection, e-stop, battery level and tilt
+  config dump                         the configuration spine is running with";
+
+struct Options {
+    json: bool,
+    socket: String,
+    timeout: Duration,
+    interval: Duration,
+}
+impl Options {
+    async fn connect(&self) -> Result<Client> {
+        Ok(Client::connect(&self.socket)
+            .await?
+            .with_timeout(self.timeout))
+    }
+}
+
+/// Prints `value` as JSON or `shown` for people
+fn print_reading(
+    options: &Options,
+    kind: &str,
+    name: &str,
+    value: &impl Serialize,
+    shown: impl Display,
+) {
+    if options.json {
+        println!("{}", json!({ kind: name, "value": value }));
+    } else {
+        println!("{}: {}", name, shown);
+    }
+}
+
+/// A reading as `value` or `value unit`
+fn show(reading: &Reading) -> String {
+    match reading {
+        Reading::Raw(raw) => raw.to_string(),
+        Reading::Measurement { value, unit } => format!("{} {}", value, unit),
+    }
+}
+
+fn print_ok(options: &Options) {
+    if !options.json {
+        println!("ok");
+    }
+}
+
+fn print_status(options: &Options, status: &SystemStatus) -> Result<()> {
+    if options.json {
+        println!("{}", serde_json::to_string(status)?);
+        return Ok(());
+    }
+    let yes_no = |on: bool| if on { "yes" } else { "no" };
+    println!("PAD connected: {}", yes_no(status.pad_connected));
+    println!(
+        "E-stop: {}",
+        if status.estop { "engaged" } else { "released" }
+    );
+    let battery = match status.battery {
+        BatteryLevel::Normal => "normal",
+        BatteryLevel::Low => "low",
+        BatteryLevel::Cutoff => "cutoff",
+    };
+    println!("Battery: {}", battery);
+    println!("Tilted: {}", yes_no(status.tilted));
+    Ok(())
+}
+
+fn parse<T: std::str::FromStr>(what: &str, value: Option<&String>) -> Result<T>
+where
+    T::Err: std::fmt::Display,
+{
+    let value = value.ok_or_else(|| eyre!("Missing {}\n\n{}", what, USAGE))?;
+    value
+        .parse()
+        .map_err(|e| eyre!("Bad {} {:?}: {}", what, value, e))
+}
+
+fn name<'a>(what: &str, value: Option<&'a String>) -> Result<&'a str> {
+    value
+        .map(String::as_str)
+        .ok_or_else(|| eyre!("Missing {}\n\n{}", what, USAGE))
+}
+
+async fn set_led(client: &Client, led: &str, state: &[String]) -> Result<()> {
+    let pattern = match state.first().map(String::as_str) {
+        Some("on") => return client.led_write(led, 1).await,
+        Some("off") => return client.led_write(led, 0).await,
+        Some("solid") => LedPattern::Solid,
+        Some("heartbeat") => LedPattern::Heartbeat,
+        Some("blink") => LedPattern::Blink {
+            hz: parse("blink rate", state.get(1))?,
+        },
+        Some("pulse") => LedPattern::Pulse {
+            count: parse("pulse count", state.get(1))?,
+        },
+        _ => return Err(eyre!("Unknown LED state {:?}\n\n{}", state, USAGE)),
+    };
+    if !pattern.is_valid() {
+        return Err(eyre!("Blink rate has to be positive, got {:?}", pattern));
+    }
+    client.led_pattern(led, pattern).await
+}
+
+async fn run(options: &Options, words: &[String]) -> Result<()> {
+    let word = |i: usize| words.get(i).map(String::as_str);
+    match (word(0), word(1)) {
+        (Some("motor"), Some("set")) => {
+            let motor = name("motor", words.get(2))?;
+            let speed: f32 = parse("speed", words.get(3))?;
+            options.connect().await?.motor_set(motor, speed).await?;
+            print_ok(options);
+        }
+        (Some("servo"), Some("set")) => {
+            let servo = name("servo", words.get(2))?;
+            let position = name("position", words.get(3))?;
+            let client = options.connect().await?;
+            match position.strip_suffix("deg") {
+                Some(degrees) => {
+                    let degrees: f32 = parse("angle", Some(&degrees.to_string()))?;
+                    client.servo_set_angle(servo, degrees).await?
+                }
+                None => {
+                    client
+                        .servo_write(servo, parse("position", words.get(3))?)
+                        .await?
+                }
+            }
+            print_ok(options);
+        }
+        (Some("encoder"), Some(action @ ("read" | "watch"))) => {
+            let encoder = name("encoder", words.get(2))?;
+            let client = options.connect().await?;
+            loop {
+                let reading = client.encoder_read(encoder).await?;
+                print_reading(options, "encoder", encoder, &reading, show(&reading));
+                if action == "read" {
+                    break;
+                }
+                tokio::time::sleep(options.interval).await;
+            }
+        }
+        (Some("switch"), Some(action @ ("read" | "watch"))) => {
+            let switch = name("switch", words.get(2))?;
+            let client = options.connect().await?;
+            let mut last = None;
+            loop {
+                let on = client.switch_read(switch).await?;
+                if last != Some(on) {
+                    let state = if on { "on" } else { "off" };
+                    print_reading(options, "switch", switch, &on, state);
+                }
+                if action == "read" {
+                    break;
+                }
+                last = Some(on);
+                tokio::time::sleep(options.interval).await;
+            }
+        }
+        (Some("gpio"), Some("read")) => {
+            let pin: u64 = parse("GPIO", words.get(2))?;
+            let value = options.connect().await?.gpio_read(pin).await?;
+            print_reading(options, "gpio", &pin.to_string(), &value, value);
+        }
+        (Some("led"), Some("set")) => {
+            let led = name("LED", words.get(2))?;
+            set_led(&options.connect().await?, led, &words[3..]).await?;
+            print_ok(options);
+        }
+        (Some("sensor"), Some("read")) => {
+            let sensor = name("sensor", words.get(2))?;
+            let reading = options.connect().await?.sensor_read(sensor).await?;
+            print_reading(options, "sensor", sensor, &reading, show(&reading));
+        }
+        (Some("status"), None) => {
+            print_status(options, &options.connect().await?.status().await?)?;
+        }
+        (Some("config"), Some("dump")) => {
+            let config = options.connect().await?.config_dump().await?;
+            if options.json {
+                println!("{}", config);
+            } else {
+                println!("{}", serde_json::to_string_pretty(&config)?);
+            }
+        }
+        (None, _) => println!("{}", USAGE),
+        _ => return Err(eyre!("Unknown command {:?}\n\n{}", words.join(" "), USAGE)),
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let mut options = Options {
+        json: false,
+        socket: DEFAULT_SOCKET.to_string(),
+        timeout: Duration::from_secs(1),
+        interval: Duration::from_millis(100),
+    };
+    let mut words = Vec::new();
+    let mut args = std::env::args().skip(1);
+    let parsed = (|| -> Result<bool> {
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--json" => options.json = true,
+                "--socket" => {
+                    options.socket = name("socket path", args.next().as_ref())?.to_string()
+                }
+                "--timeout" => {
+                    options.timeout = Duration::from_millis(parse("timeout", args.next().as_ref())?)
+                }
+                "--interval" => {
+                    options.interval =
+                        Duration::from_millis(parse("interval", args.next().as_ref())?)
+                }
+                "-h" | "--help" => {
+                    println!("{}", USAGE);
+                    return Ok(false);
+                }
+                _ => words.push(arg),
+            }
+        }
+        Ok(true)
+    })();
+    let result = match parsed {
+        Ok(true) => run(&options, &words).await,
+        Ok(false) => Ok(()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        eprintln!("spine-ctl: {}", e);
+        std::process::exit(1);
+    }
+}
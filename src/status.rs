@@ -0,0 +1,194 @@
+use crate::battery::BatteryLevel;
+use crate::gpio::GpioPin;
+use std::collections::HashMap;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{Duration, Instant};
+use tracing::{debug, error};
+
+const TICK: Duration = Duration::from_millis(25);
+
+pub use spine_client::{Colour, LedPattern, SystemStatus};
+
+/// What the status LEDs show for `status`, the most urgent condition winning
+fn indication(status: &SystemStatus) -> (Colour, LedPattern) {
+    if status.estop {
+        (Colour::Red, LedPattern::Blink { hz: 8.0 })
+    } else if status.tilted {
+        (Colour::Magenta, LedPattern::Blink { hz: 4.0 })
+    } else if status.battery == BatteryLevel::Cutoff {
+        (Colour::Red, LedPattern::Pulse { count: 3 })
+    } else if !status.pad_connected {
+        (Colour::Amber, LedPattern::Solid)
+    } else if status.battery == BatteryLevel::Low {
+        (Colour::Amber, LedPattern::Blink { hz: 1.0 })
+    } else {
+        (Colour::Green, LedPattern::Heartbeat)
+    }
+}
+
+/// Whether an LED showing `pattern` is lit `elapsed` into it. Blinks faster than the indicator
+/// ticks are shown at the fastest rate it can manage.
+fn is_on(pattern: LedPattern, elapsed: Duration) -> bool {
+    let ms = elapsed.as_millis() as u64;
+    match pattern {
+        LedPattern::Off => false,
+        LedPattern::Solid => true,
+        LedPattern::Blink { hz } => {
+            let fastest = 2 * TICK.as_millis() as u64;
+            let period = ((1000.0 / hz.max(0.1)) as u64).max(fastest);
+            ms % period < period / 2
+        }
+        LedPattern::Heartbeat => matches!(ms % 1000, 0..=99 | 200..=299),
+        LedPattern::Pulse { count } => {
+            let phase = ms % (count as u64 * 300 + 1000);
+            phase < count as u64 * 300 && phase % 300 < 150
+        }
+    }
+}
+
+/// The entries of `system.status_leds` mixed to make `colour`
+fn channels(colour: Colour) -> &'static [&'static str] {
+    match colour {
+        Colour::Red => &["red"],
+        Colour::Green => &["green"],
+        Colour::Blue => &["blue"],
+        Colour::Amber => &["red", "green"],
+        Colour::Cyan => &["green", "blue"],
+        Colour::Magenta => &["red", "blue"],
+        Colour::White => &["red", "green", "blue"],
+    }
+}
+
+#[derive(Debug)]
+pub enum StatusCommand {
+    /// Take over a single LED
+    Led { led: String, pattern: LedPattern },
+    /// Take over the red, green and blue LEDs
+    Indicate { colour: Colour, pattern: LedPattern },
+    /// Give every LED back to the system state indication
+    Release,
+}
+
+/// Drives `pins` from a background task, showing `status` unless a client has taken over an LED
+pub fn spawn_indicator(
+    pins: HashMap<String, GpioPin>,
+    mut status: watch::Receiver<SystemStatus>,
+) -> mpsc::Sender<StatusCommand> {
+    let (tx, mut rx) = mpsc::channel::<StatusCommand>(16);
+    tokio::spawn(async move {
+        let mut overrides: HashMap<String, LedPattern> = HashMap::new();
+        let mut automatic = indication(&status.borrow());
+        let mut last_values: HashMap<String, u8> = HashMap::new();
+        let start = Instant::now();
+        let mut interval = tokio::time::interval(TICK);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                command = rx.recv() => {
+                    debug!("Status LED command: {:?}", command);
+                    match command {
+                        Some(StatusCommand::Led { led, pattern }) => {
+                            overrides.insert(led, pattern);
+                        }
+                        Some(StatusCommand::Indicate { colour, pattern }) => {
+                            for led in ["red", "green", "blue"] {
+                                let pattern = if channels(colour).contains(&led) { pattern } else { LedPattern::Off };
+                                overrides.insert(led.to_string(), pattern);
+                            }
+                        }
+                        Some(StatusCommand::Release) => overrides.clear(),
+                        None => break,
+                    }
+                }
+                changed = status.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                    automatic = indication(&status.borrow());
+                    debug!("System status changed, indicating {:?}", automatic);
+                }
+            }
+            let elapsed = start.elapsed();
+            for (name, pin) in &pins {
+                let (colour, pattern) = automatic;
+                let pattern = overrides.get(name).copied().unwrap_or(
+                    if channels(colour).contains(&name.as_str()) { pattern } else { LedPattern::Off },
+                );
+                let value = is_on(pattern, elapsed) as u8;
+                if last_values.get(name) != Some(&value) {
+                    if let Err(e) = pin.set_value(value) {
+                        error!("Error setting status LED {}: {}", name, e);
+                    }
+                    last_values.insert(name.clone(), value);
+                }
+            }
+        }
+    });
+    tx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn blink_is_on_for_the_first_half_of_its_period() {
+        let blink = LedPattern::Blink { hz: 2.0 };
+        assert!(is_on(blink, at(0)));
+        assert!(is_on(blink, at(249)));
+        assert!(!is_on(blink, at(250)));
+        assert!(!is_on(blink, at(499)));
+        assert!(is_on(blink, at(500)));
+    }
+
+    #[test]
+    fn blink_faster_than_the_tick_is_clamped() {
+        for hz in [1000.0, 2000.0, f32::MAX] {
+            let blink = LedPattern::Blink { hz };
+            assert!(is_on(blink, at(0)));
+            assert!(!is_on(blink, TICK));
+            assert!(is_on(blink, 2 * TICK));
+        }
+    }
+
+    #[test]
+    fn blink_slower_than_a_tenth_of_a_hz_is_clamped() {
+        let blink = LedPattern::Blink { hz: 0.0 };
+        assert!(is_on(blink, at(4999)));
+        assert!(!is_on(blink, at(5000)));
+    }
+
+    #[test]
+    fn pulse_flashes_count_times_then_pauses() {
+        let pulse = LedPattern::Pulse { count: 2 };
+        let lit: Vec<bool> = (0..16).map(|i| is_on(pulse, at(i * 100))).collect();
+        assert_eq!(
+            lit,
+            [
+                true, true, false, true, true, false, false, false, false, false, false, false,
+                false, false, false, false
+            ]
+        );
+        assert!(is_on(pulse, at(1600)));
+    }
+
+    #[test]
+    fn pulse_of_zero_stays_off() {
+        let pulse = LedPattern::Pulse { count: 0 };
+        assert!((0..20).all(|i| !is_on(pulse, at(i * 50))));
+    }
+
+    #[test]
+    fn heartbeat_and_solid() {
+        assert!(is_on(LedPattern::Heartbeat, at(50)));
+        assert!(!is_on(LedPattern::Heartbeat, at(150)));
+        assert!(is_on(LedPattern::Heartbeat, at(250)));
+        assert!(!is_on(LedPattern::Heartbeat, at(500)));
+        assert!(is_on(LedPattern::Solid, at(123)));
+        assert!(!is_on(LedPattern::Off, at(123)));
+    }
+}
@@ -0,0 +1,29 @@
+use crate::server::HardwareResponse;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::sync::watch;
+
+/// Latest value of every topic spine publishes, for clients to read or subscribe to
+#[derive(Default)]
+pub struct Telemetry {
+    topics: Mutex<HashMap<String, watch::Sender<Option<HardwareResponse>>>>,
+}
+impl Telemetry {
+    fn with_topic<T>(&self, topic: &str, f: impl FnOnce(&watch::Sender<Option<HardwareResponse>>) -> T) -> T {
+        let mut topics = self.topics.lock().unwrap();
+        let sender = topics
+            .entry(topic.to_string())
+            .or_insert_with(|| watch::channel(None).0);
+        f(sender)
+    }
+    pub fn publish(&self, topic: &str, value: HardwareResponse) {
+        self.with_topic(topic, |sender| sender.send_replace(Some(value)));
+    }
+    /// Topics can be subscribed to before anything is published on them
+    pub fn subscribe(&self, topic: &str) -> watch::Receiver<Option<HardwareResponse>> {
+        self.with_topic(topic, |sender| sender.subscribe())
+    }
+    pub fn latest(&self, topic: &str) -> Option<HardwareResponse> {
+        self.with_topic(topic, |sender| sender.borrow().clone())
+    }
+}
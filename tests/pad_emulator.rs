@@ -0,0 +1,156 @@
+// Runs spine against pad_emulator on a pty, checking requests make it through the real serial code
+use spine_client::{Client, Reading};
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+const WAIT: Duration = Duration::from_secs(10);
+
+/// Kills the process when dropped, so a failed assertion doesn't leave it running
+struct Killed(Child);
+impl Drop for Killed {
+    fn drop(&mut self) {
+        self.0.kill().ok();
+        self.0.wait().ok();
+    }
+}
+
+struct Emulator {
+    _process: Killed,
+    stdin: ChildStdin,
+    lines: mpsc::Receiver<String>,
+}
+impl Emulator {
+    fn start(link: &Path) -> Self {
+        let mut process = Command::new(env!("CARGO_BIN_EXE_pad_emulator"))
+            .arg("--link")
+            .arg(link)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("pad_emulator should start");
+        let stdin = process.stdin.take().unwrap();
+        let stdout = process.stdout.take().unwrap();
+        let (send, lines) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                if send.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        let mut emulator = Self {
+            _process: Killed(process),
+            stdin,
+            lines,
+        };
+        emulator.expect(&format!("Linked {} to it", link.display()));
+        emulator
+    }
+    /// Waits for the emulator to print `line`, skipping everything else it prints
+    fn expect(&mut self, line: &str) {
+        let deadline = Instant::now() + WAIT;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match self.lines.recv_timeout(left) {
+                Ok(printed) if printed == line => return,
+                Ok(_) => {}
+                Err(e) => panic!("pad_emulator did not print {:?}: {}", line, e),
+            }
+        }
+    }
+    fn command(&mut self, command: &str) {
+        writeln!(self.stdin, "{}", command).unwrap();
+    }
+}
+
+fn config(pad: &Path) -> String {
+    format!(
+        r#"
+[pad]
+path = "{}"
+[pad.motors]
+drive_front = {{ port = 0, channel = 1 }}
+drive_rear = {{ port = 0, channel = 2 }}
+[pad.encoders]
+drive_front_left = 2
+[pad.servos]
+
+[system]
+gpio = "mock"
+pca9685_path = "/nonexistent"
+[system.motors]
+[system.limit_switches]
+[system.status_leds]
+[system.servos]
+"#,
+        pad.display()
+    )
+}
+
+fn start_spine(dir: &Path, socket: &Path) -> Killed {
+    let process = Command::new(env!("CARGO_BIN_EXE_spine"))
+        .arg("--socket")
+        .arg(socket)
+        .env("XDG_CONFIG_HOME", dir)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("spine should start");
+    Killed(process)
+}
+
+async fn connect(socket: &Path) -> Client {
+    let deadline = Instant::now() + WAIT;
+    loop {
+        match Client::connect(socket).await {
+            Ok(client) => return client,
+            Err(e) if Instant::now() > deadline => panic!("Could not connect to spine: {}", e),
+            Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+        }
+    }
+}
+
+#[tokio::test]
+async fn spine_talks_to_the_emulated_pad() {
+    let dir: PathBuf = std::env::temp_dir().join(format!("spine-pad-emulator-{}", std::process::id()));
+    fs::create_dir_all(dir.join("spine")).unwrap();
+    let link = dir.join("pad");
+    let socket = dir.join("hardware.sock");
+
+    let mut emulator = Emulator::start(&link);
+    fs::write(dir.join("spine/config.toml"), config(&link)).unwrap();
+    let _spine = start_spine(&dir, &socket);
+    emulator.expect("Received VersionReport");
+    // Sent last in the handshake, once the PAD has replied to VersionReport
+    emulator.expect("Received PwmFrequencyWrite(60)");
+    let client = connect(&socket).await;
+
+    client.motor_set("drive_front", 1.0).await.unwrap();
+    emulator.expect("Received SabertoothWrite(0, 127)");
+    client.motor_set("drive_rear", -1.0).await.unwrap();
+    emulator.expect("Received SabertoothWrite(0, 128)");
+    client.motor_write("drive_front", vec![100]).await.unwrap();
+    emulator.expect("Received SabertoothWrite(0, 100)");
+
+    // Counted from the first reading after spine connected
+    let reading = client.encoder_read("drive_front_left").await.unwrap();
+    emulator.expect("Received EncoderRead");
+    assert_eq!(reading, Reading::Raw(0));
+    emulator.command("encoder 2 1234");
+    let deadline = Instant::now() + WAIT;
+    loop {
+        let reading = client.encoder_read("drive_front_left").await.unwrap();
+        emulator.expect("Received EncoderRead");
+        if reading == Reading::Raw(1234) {
+            break;
+        }
+        assert!(Instant::now() < deadline, "Encoder still read {:?}", reading);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    fs::remove_dir_all(&dir).ok();
+}